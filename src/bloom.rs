@@ -0,0 +1,90 @@
+use crate::engine::TxId;
+use crate::rng::SplitMix64;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// A fixed size bloom filter over transaction Ids. It answers whether an Id has possibly been seen
+/// before using far less memory than storing every Id, at the cost of occasionally reporting an
+/// Id as seen when it hasn't been (a false positive). It never misses an Id that was inserted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Sizes a filter so that once `expected_items` Ids have been inserted the chance of a false
+    /// positive is roughly `false_positive_rate`.
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let expected_items = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-expected_items * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / expected_items) * ln2).round().max(1.0) as u32;
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    pub fn insert(&mut self, id: TxId) {
+        for bit in self.bit_indexes(id) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Whether the Id has possibly been inserted before.
+    pub fn contains(&self, id: TxId) -> bool {
+        self.bit_indexes(id)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits an Id maps to, derived from two independent hashes of the Id by double hashing.
+    fn bit_indexes(&self, id: TxId) -> impl Iterator<Item = u64> {
+        // Only a conversion when `TxId` is narrower than u64
+        #[allow(clippy::useless_conversion)]
+        let first = mix(u64::from(id));
+        let second = mix(first) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64)
+            .map(move |i| first.wrapping_add(i.wrapping_mul(second)) % num_bits)
+    }
+}
+
+/// The first output of splitmix64 seeded with the value, which spreads sequential Ids evenly
+/// across the filter.
+fn mix(value: u64) -> u64 {
+    SplitMix64::new(value).next_u64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_ids_are_always_found() {
+        let mut filter = BloomFilter::new(1_000, 0.01);
+        for id in (0..1_000).map(|id| id * 7) {
+            filter.insert(id);
+        }
+        assert!((0..1_000).map(|id| id * 7).all(|id| filter.contains(id)));
+    }
+
+    #[test]
+    fn false_positive_rate_is_close_to_target() {
+        let items = 100_000;
+        let mut filter = BloomFilter::new(items, 0.01);
+        for id in 0..items as TxId {
+            filter.insert(id);
+        }
+        // None of these were inserted so every hit is a false positive
+        let false_positives = (items as TxId..2 * items as TxId)
+            .filter(|id| filter.contains(*id))
+            .count();
+        let rate = false_positives as f64 / items as f64;
+        assert!(rate < 0.02, "false positive rate was {}", rate);
+    }
+}
@@ -1,39 +1,173 @@
+use crate::bloom::BloomFilter;
+use crate::error::EngineError;
+use crate::error::ErrorPolicy;
+use crate::rng::SplitMix64;
 use anyhow::{Context, Error};
+use csv::StringRecord;
 use rust_decimal::prelude::FromStr;
 use rust_decimal::Decimal;
 use serde::Deserialize;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::fmt;
 use std::fmt::Display;
 use std::fmt::Formatter;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use std::time::Instant;
 
-#[derive(Debug, Deserialize)]
+/// Records a step of processing when the engine is tracing for `explain`. The message is only
+/// formatted while tracing.
+macro_rules! trace {
+    ($engine:ident, $($arg:tt)*) => {
+        if let Some(trace) = $engine.trace.as_mut() {
+            trace.push(format!($($arg)*));
+        }
+    };
+}
+
+/// The type of transaction Ids. This is `u32` by default and widened to `u64` by the
+/// `wide-tx-id` feature for sources whose Ids don't fit in 32 bits.
+#[cfg(not(feature = "wide-tx-id"))]
+pub type TxId = u32;
+#[cfg(feature = "wide-tx-id")]
+pub type TxId = u64;
+
+/// The header `process_lines` adds in front of the lines it is given.
+const INPUT_HEADER: &str = "type,client,tx,amount";
+
+/// The columns every input source must name in its header row. The `amount` column may be left
+/// out entirely for inputs that only contain disputes, resolves and chargebacks.
+const REQUIRED_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+
+/// The header row of the account CSV output.
+const OUTPUT_HEADER: &str = "client,available,held,total,locked";
+
+/// The UTF-8 byte order mark, which Excel needs to open CSV as UTF-8.
+const UTF8_BOM: &str = "\u{feff}";
+
+/// The version of the account CSV output format, changed whenever its columns or their meaning
+/// change.
+pub const SCHEMA_VERSION: &str = "v1";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
-    #[serde(rename(deserialize = "type"))]
+    #[serde(rename = "type")]
     tx_type: TransactionType,
-    #[serde(rename(deserialize = "client"))]
+    #[serde(rename = "client")]
     client_id: u16,
-    #[serde(rename(deserialize = "tx"))]
-    tx_id: u32,
+    #[serde(rename = "tx")]
+    tx_id: TxId,
     amount: Option<String>,
+    // Marks test data, an empty or missing `sandbox` column is treated as false. This is always
+    // serialized, even when empty, so formats that aren't self-describing can read it back.
+    #[serde(default)]
+    sandbox: Option<bool>,
+    // A key identifying the request behind the transaction independently of its Id
+    #[serde(default)]
+    idempotency_key: Option<String>,
 }
 
 impl Transaction {
+    /// Builds a transaction directly for library users who aren't reading from CSV. The amount
+    /// should be `None` for disputes, resolves and chargebacks.
+    pub fn new(
+        tx_type: TransactionType,
+        client_id: u16,
+        tx_id: TxId,
+        amount: Option<Decimal>,
+    ) -> Self {
+        Self {
+            tx_type,
+            client_id,
+            tx_id,
+            amount: amount.map(|amt| amt.to_string()),
+            sandbox: None,
+            idempotency_key: None,
+        }
+    }
+
+    pub fn tx_type(&self) -> TransactionType {
+        self.tx_type
+    }
+
+    pub fn client_id(&self) -> u16 {
+        self.client_id
+    }
+
+    pub fn tx_id(&self) -> TxId {
+        self.tx_id
+    }
+
+    /// Marks the transaction as sandbox test data, see `DataMode`.
+    pub fn with_sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Tags the transaction with an idempotency key, see `with_idempotency_keys`.
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    fn is_sandbox(&self) -> bool {
+        self.sandbox.unwrap_or(false)
+    }
+
     /// Used to convert the transaction amount to a decimal number so we can perform math on it.
+    /// Only deposits and withdrawals carry an amount, disputes, resolves and chargebacks refer to
+    /// the amount of the transaction they reference so any amount given on them is never read.
     fn amount(&self) -> anyhow::Result<Decimal> {
+        if !self.tx_type.carries_amount() {
+            return Err(Error::msg(format!(
+                "A {:?} transaction doesn't carry an amount",
+                self.tx_type
+            )));
+        }
         let amount = self.amount.as_ref().context("Amount was empty")?;
-        Decimal::from_str(amount).context("Failed to deserialize amount")
+        match Decimal::from_str(amount) {
+            Ok(amount) => Ok(amount),
+            Err(_) if has_trailing_characters(amount) => {
+                Err(EngineError::MalformedAmount(amount.clone()).into())
+            }
+            Err(err) => Err(err).context("Failed to deserialize amount"),
+        }
     }
 }
 
+/// Whether the amount starts out as a number but carries on with characters that can't be part of
+/// one, such as `1.00abc`.
+fn has_trailing_characters(amount: &str) -> bool {
+    let unsigned = amount
+        .strip_prefix(|c| c == '+' || c == '-')
+        .unwrap_or(amount);
+    let numeric_len = unsigned
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(unsigned.len());
+    numeric_len > 0 && numeric_len < unsigned.len()
+}
+
 #[cfg(test)]
 impl Transaction {
     // A useful constructor for testing
     fn from(
         tx_type: TransactionType,
         client_id: u16,
-        tx_id: u32,
+        tx_id: TxId,
         amount: Option<impl Into<String>>,
     ) -> Self {
         let amount: Option<String> = amount.map(|amt| amt.into());
@@ -42,343 +176,6900 @@ impl Transaction {
             client_id,
             tx_id,
             amount,
+            sandbox: None,
+            idempotency_key: None,
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
-enum TransactionType {
-    #[serde(rename(deserialize = "deposit"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransactionType {
+    #[serde(rename = "deposit")]
     Deposit,
-    #[serde(rename(deserialize = "withdrawal"))]
+    #[serde(rename = "withdrawal")]
     Withdrawal,
-    #[serde(rename(deserialize = "dispute"))]
+    #[serde(rename = "dispute")]
     Dispute,
-    #[serde(rename(deserialize = "resolve"))]
+    #[serde(rename = "resolve")]
     Resolve,
-    #[serde(rename(deserialize = "chargeback"))]
+    #[serde(rename = "chargeback")]
     Chargeback,
+    /// Locks the client's account directly, the transaction Id is not used
+    #[serde(rename = "lock")]
+    Lock,
+    /// Unlocks the client's account if it was locked with `Lock` or restored locked, the
+    /// transaction Id is not used. Accounts locked by a chargeback stay locked.
+    #[serde(rename = "unlock")]
+    Unlock,
+}
+
+impl TransactionType {
+    /// The name of the transaction type as it appears in the input.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Lock => "lock",
+            TransactionType::Unlock => "unlock",
+        }
+    }
+
+    /// Whether transactions of this type carry their own amount.
+    fn carries_amount(&self) -> bool {
+        matches!(self, TransactionType::Deposit | TransactionType::Withdrawal)
+    }
 }
 
-#[derive(Default, Debug, Clone, Copy)]
-struct Account {
+/// Balances are always held at the full precision of the amounts that produced them. Rounding is
+/// only ever applied when an account is formatted for output, never when the balances are mutated.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Account {
     available: Decimal,
     held: Decimal,
     total: Decimal,
     locked: bool,
 }
 
-#[derive(Debug)]
+/// Where the engine keeps client accounts, indexed by the client Id. The engine only ever reads
+/// accounts out and writes them back whole so a store can be backed by a database or sharded
+/// across machines for very large numbers of clients. A `HashMap` is used by default.
+pub trait AccountStore: fmt::Debug + Clone + Default {
+    /// A copy of the client's account, if they have one.
+    fn get(&self, client_id: u16) -> Option<Account>;
+
+    /// Stores the client's account, replacing and returning any account they already had.
+    fn insert(&mut self, client_id: u16, account: Account) -> Option<Account>;
+
+    /// Every account in the store with its client Id, in any order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (u16, Account)> + '_>;
+}
+
+impl AccountStore for HashMap<u16, Account> {
+    fn get(&self, client_id: u16) -> Option<Account> {
+        HashMap::get(self, &client_id).copied()
+    }
+
+    fn insert(&mut self, client_id: u16, account: Account) -> Option<Account> {
+        HashMap::insert(self, client_id, account)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (u16, Account)> + '_> {
+        Box::new(HashMap::iter(self).map(|(id, account)| (*id, *account)))
+    }
+}
+
+/// A row of the account CSV the engine outputs, used to restore accounts from a previous run.
+#[derive(Debug, Deserialize)]
+struct AccountRow {
+    client: u16,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+impl AccountRow {
+    fn into_account(self) -> anyhow::Result<(u16, Account)> {
+        let parse = |value: &str| Decimal::from_str(value).context("Failed to deserialize balance");
+        let account = Account {
+            available: parse(&self.available)?,
+            held: parse(&self.held)?,
+            total: parse(&self.total)?,
+            locked: self.locked,
+        };
+        Ok((self.client, account))
+    }
+}
+
+/// A change to an account's balances or locked state made by applying a transaction, sent to the
+/// channel given to `with_event_channel`.
+#[derive(Debug, Clone)]
+pub struct BalanceEvent {
+    pub tx_id: TxId,
+    pub tx_type: TransactionType,
+    /// The state of the account after the transaction was applied
+    pub account: AccountWithId,
+}
+
+/// A chargeback which locked a client's account, as reported by `lock_report`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockRecord {
+    pub client_id: u16,
+    /// The Id of the disputed transaction that was charged back
+    pub tx_id: TxId,
+    /// The amount that was charged back
+    pub amount: Decimal,
+    /// The state of the account straight after it was locked
+    pub account: AccountWithId,
+}
+
+/// What happened when the engine processed a single transaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcessOutcome {
+    /// The transaction was applied to the client's account
+    Applied,
+    /// Ignored as it isn't accepted by the engine's `DataMode`
+    FilteredByDataMode,
+    /// Dropped as a retransmit of a dispute message seen within the dedup window
+    DuplicateDisputeMessage,
+    /// Dropped as its idempotency key has already been seen
+    DuplicateIdempotencyKey,
+    /// Ignored as the client's account is locked
+    AccountLocked,
+    /// A withdrawal for more than the client's available funds was rejected
+    InsufficientFunds,
+    /// A dispute, resolve or chargeback referred to a transaction that isn't known
+    UnknownTransaction,
+    /// A resolve or chargeback referred to a transaction that isn't disputed
+    ResolveWithoutDispute,
+    /// A resolve was repeated for a transaction whose dispute it already settled
+    DuplicateResolve,
+    /// Processing the transaction failed with the given error and it had no effect
+    Failed(EngineError),
+}
+
+/// The net change to a single account over a batch of transactions.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AccountDiff {
+    pub id: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// The new locked state if it changed, otherwise `None`
+    pub locked: Option<bool>,
+}
+
+impl AccountDiff {
+    fn between(id: u16, before: &Account, after: &Account) -> Self {
+        Self {
+            id,
+            available: after.available - before.available,
+            held: after.held - before.held,
+            total: after.total - before.total,
+            locked: if before.locked != after.locked {
+                Some(after.locked)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AccountWithId {
     id: u16,
     account: Account,
 }
 
+impl AccountWithId {
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn available(&self) -> Decimal {
+        self.account.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.account.held
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.account.total
+    }
+
+    pub fn locked(&self) -> bool {
+        self.account.locked
+    }
+
+    /// Formats the available, held and total balances for output at the given scales. Available
+    /// and held are cut off at their scale while total is rounded to it.
+    fn formatted_balances(&self, scales: &OutputScales) -> (String, String, String) {
+        (
+            format!("{:.*}", scales.available as usize, self.account.available),
+            format!("{:.*}", scales.held as usize, self.account.held),
+            format!(
+                "{:.*}",
+                scales.total as usize,
+                self.account.total.round_dp(scales.total)
+            ),
+        )
+    }
+}
+
+/// Compares two accounts after rounding each balance to the given scale, so accounts that only
+/// differ by dust below that scale are considered equal. Client Ids and locked states must match
+/// exactly.
+pub fn accounts_equal_at_scale(a: &AccountWithId, b: &AccountWithId, scale: u32) -> bool {
+    a.id == b.id
+        && a.account.locked == b.account.locked
+        && a.account.available.round_dp(scale) == b.account.available.round_dp(scale)
+        && a.account.held.round_dp(scale) == b.account.held.round_dp(scale)
+        && a.account.total.round_dp(scale) == b.account.total.round_dp(scale)
+}
+
+/// Processes each input CSV with its own default engine and returns the difference (`b` minus
+/// `a`) for every client whose account differs between the two, sorted by Id, as a
+/// reconciliation tool. A client missing from either side is treated as an empty account. Fails
+/// if either input can't be processed.
+pub fn diff_files<R1: Read, R2: Read>(a: R1, b: R2) -> anyhow::Result<Vec<AccountDiff>> {
+    let mut engine_a = TransactionEngine::new();
+    engine_a
+        .process_reader(a)
+        .context("Failed to process first input")?;
+    let mut engine_b = TransactionEngine::new();
+    engine_b
+        .process_reader(b)
+        .context("Failed to process second input")?;
+    Ok(account_diffs(&engine_a.accounts, &engine_b.accounts))
+}
+
+/// The difference from `before` to `after` for every client whose account differs, sorted by Id.
+fn account_diffs(
+    before: &HashMap<u16, Account>,
+    after: &HashMap<u16, Account>,
+) -> Vec<AccountDiff> {
+    let mut ids: Vec<u16> = before.keys().chain(after.keys()).copied().collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids.into_iter()
+        .filter_map(|id| {
+            let before = before.get(&id).copied().unwrap_or_default();
+            let after = after.get(&id).copied().unwrap_or_default();
+            if before == after {
+                None
+            } else {
+                Some(AccountDiff::between(id, &before, &after))
+            }
+        })
+        .collect()
+}
+
 impl Display for AccountWithId {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (available, held, total) = self.formatted_balances(&OutputScales::default());
         write!(
             f,
-            "{},{:.4},{:.4},{:.4},{}",
-            self.id,
-            self.account.available,
-            self.account.held,
-            self.account.total.round_dp(4),
-            self.account.locked
+            "{},{},{},{},{}",
+            self.id, available, held, total, self.account.locked
         )
     }
 }
 
-#[derive(Debug)]
-pub struct TransactionEngine {
-    // The state of every account indexed by the account Id
+/// The number of decimal places each balance column is output with, 4 for every column by
+/// default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputScales {
+    pub available: u32,
+    pub held: u32,
+    pub total: u32,
+}
+
+impl Default for OutputScales {
+    fn default() -> Self {
+        Self {
+            available: 4,
+            held: 4,
+            total: 4,
+        }
+    }
+}
+
+/// A column of the account CSV output, see `write_accounts_columns`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountColumn {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+impl AccountColumn {
+    /// The name of the column in the header row.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountColumn::Client => "client",
+            AccountColumn::Available => "available",
+            AccountColumn::Held => "held",
+            AccountColumn::Total => "total",
+            AccountColumn::Locked => "locked",
+        }
+    }
+}
+
+/// Which transactions the engine accepts based on the optional `sandbox` column, keeping test data
+/// and real data apart.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DataMode {
+    /// Every transaction is processed whether or not it is marked as sandbox data
+    #[default]
+    Mixed,
+    /// Transactions marked as sandbox data are ignored
+    Production,
+    /// Only transactions marked as sandbox data are processed, all others are ignored
+    Sandbox,
+}
+
+impl DataMode {
+    fn accepts(self, tx: &Transaction) -> bool {
+        match self {
+            DataMode::Mixed => true,
+            DataMode::Production => !tx.is_sandbox(),
+            DataMode::Sandbox => tx.is_sandbox(),
+        }
+    }
+}
+
+/// How a resolve for more than the account holds is handled. Releasing more than is held would
+/// take held below zero and so leave available above total, which should never happen as held is
+/// the difference between the two.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveShortfallPolicy {
+    /// The full disputed amount is released regardless
+    #[default]
+    Allow,
+    /// The resolve fails with a `ResolveExceedsHeld` error and has no effect
+    Reject,
+    /// Only what is held is released, leaving held at zero and available equal to total
+    Clamp,
+}
+
+/// How a chargeback that would take an account's total below zero is handled. This happens when a
+/// deposit is charged back after being spent, for example into an overdraft.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NegativeTotalPolicy {
+    /// The chargeback is applied, leaving the total negative
+    #[default]
+    Allow,
+    /// The chargeback is applied and the client is recorded in `negative_total_chargebacks`
+    Flag,
+    /// The chargeback fails with a `ChargebackExceedsTotal` error and has no effect
+    Reject,
+}
+
+/// How accounts with no activity are output. An account has no activity when it has never had a
+/// deposit or withdrawal applied and all its balances are zero, such as one that has only been
+/// sent disputes. This applies to every account output, CSV, JSON and Arrow alike.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NoActivityOutput {
+    /// The account is output like any other
+    #[default]
+    Include,
+    /// The account is left out of the output
+    Omit,
+    /// Every account is output with an extra `no_activity` column or field, true for accounts
+    /// with no activity
+    Mark,
+}
+
+/// Whether `deposit_ledger` reports each client's deposits gross or net of chargebacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepositReporting {
+    /// Every deposit applied, including those later charged back
+    Gross,
+    /// Deposits applied minus the deposits that were charged back
+    Net,
+}
+
+/// The deposits made by a client and how much of them was charged back.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct DepositTotals {
+    deposited: Decimal,
+    charged_back: Decimal,
+}
+
+/// How many withdrawals a client has attempted and how many of them were applied.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct WithdrawalCounts {
+    attempted: usize,
+    succeeded: usize,
+}
+
+/// The result of checking that the output of every account adds up.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReconciliationReport {
+    /// The number of accounts checked
+    pub accounts: usize,
+    /// The Ids of accounts whose displayed available and held don't add up to their displayed
+    /// total, sorted by Id. This can happen when the output scales differ between columns.
+    pub display_mismatches: Vec<u16>,
+    /// The total balance summed across every unlocked account
+    pub active_total: Decimal,
+    /// The total balance summed across every locked account, i.e. the frozen funds
+    pub frozen_total: Decimal,
+}
+
+/// The problems found while processing a source with `process_and_validate_reader`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ValidationReport {
+    /// The number of records read from the source
+    pub rows: usize,
+    /// Every record that failed with the 1-based row (not counting the header) it was on, or row
+    /// zero for a bad header. These are kept whatever the error policy.
+    pub issues: Vec<(usize, EngineError)>,
+    /// Whether the whole source was processed, which it isn't when processing stopped at a
+    /// failure under `ErrorPolicy::Abort`
+    pub complete: bool,
+}
+
+/// The shape of an account in JSON output. Amounts are formatted exactly as they are in the CSV
+/// output so both formats agree on every value.
+#[derive(Debug, Serialize)]
+struct AccountRecord<'a> {
+    client: u16,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    no_activity: Option<bool>,
+}
+
+/// The column `write_accounts_with` adds after each record when accounts with no activity are
+/// marked.
+#[derive(Debug, Serialize)]
+struct NoActivityColumn {
+    no_activity: bool,
+}
+
+/// An error collected under `ErrorPolicy::Collect` in the shape it is reported as JSON.
+#[derive(Debug, Serialize)]
+struct ErrorRecord<'a> {
+    row: usize,
+    kind: &'a str,
+    message: String,
+}
+
+/// A copy of everything the engine needs to carry on processing from where it was, taken with
+/// `snapshot` and restored with `recover`. Configuration isn't included, it is taken from the
+/// engine the snapshot is restored into.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    // The sequence number of the last transaction processed before the snapshot was taken
+    sequence: u64,
     accounts: HashMap<u16, Account>,
+    transactions: HashMap<TxId, Transaction>,
+    disputed_transactions: HashSet<TxId>,
+    metadata: HashMap<u16, BTreeMap<String, String>>,
+    account_order: Vec<u16>,
+    recent_dispute_messages: VecDeque<(TransactionType, u16, TxId)>,
+    ignored_amounts: usize,
+    resolves_without_dispute: usize,
+    resolved_transactions: HashSet<TxId>,
+    charged_back_transactions: HashSet<TxId>,
+    duplicate_resolves: usize,
+    global_freeze: Option<HashSet<u16>>,
+    funded_clients: HashSet<u16>,
+    held_ratio_flagged: HashSet<u16>,
+    systemic_dispute_flagged: bool,
+    overdrawn_disputes: HashSet<u16>,
+    ever_disputed: HashSet<u16>,
+    negative_total_chargebacks: HashSet<u16>,
+    chargeback_counts: HashMap<u16, usize>,
+    deposit_totals: HashMap<u16, DepositTotals>,
+    withdrawal_counts: HashMap<u16, WithdrawalCounts>,
+    dispute_actions: HashMap<TxId, usize>,
+    lock_records: Vec<LockRecord>,
+    duplicate_filter: Option<BloomFilter>,
+    idempotency_keys: HashSet<String>,
+    history: HashMap<u16, Vec<Transaction>>,
+}
+
+impl EngineSnapshot {
+    /// The sequence number of the last transaction processed before the snapshot was taken.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Reads a snapshot written by `snapshot_to` in the given format.
+    pub fn read_from<R: Read>(reader: R, format: SnapshotFormat) -> anyhow::Result<Self> {
+        match format {
+            SnapshotFormat::Json => {
+                serde_json::from_reader(reader).context("Failed to read JSON snapshot")
+            }
+            SnapshotFormat::Bincode => {
+                bincode::deserialize_from(reader).context("Failed to read bincode snapshot")
+            }
+        }
+    }
+}
+
+/// The encoding a snapshot is written in by `snapshot_to` and read back with
+/// `EngineSnapshot::read_from`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    /// JSON, which is readable but large
+    Json,
+    /// bincode, a compact binary encoding which is smaller and quicker to read and write
+    Bincode,
+}
+
+/// A copy of the engine's internal state for debugging, see `debug_state`. Unlike
+/// `EngineSnapshot` everything is public for inspection, balances are kept at full precision and
+/// entries are sorted by Id.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DebugState {
+    pub accounts: BTreeMap<u16, DebugAccount>,
+    /// The transactions that are eligible to be disputed
+    pub transactions: BTreeMap<TxId, DebugTransaction>,
+    /// The Ids of the transactions currently in dispute
+    pub disputed: BTreeSet<TxId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DebugAccount {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DebugTransaction {
+    pub tx_type: TransactionType,
+    pub client: u16,
+    /// The amount exactly as it was given
+    pub amount: Option<String>,
+}
+
+/// An entry of the write-ahead log written by `process_transaction_logged`, one JSON object per
+/// line.
+#[derive(Debug, Serialize, Deserialize)]
+struct WalEntry<'a> {
+    seq: u64,
+    #[serde(flatten)]
+    tx: Cow<'a, Transaction>,
+}
+
+/// Randomly injects faults into records read from input, see `with_fault_injection`.
+#[derive(Debug, Clone)]
+struct FaultInjector {
+    rng: SplitMix64,
+    rate: f64,
+    injected: usize,
+}
+
+impl FaultInjector {
+    /// Decides whether to inject a fault into the next record, picking a deserialization or
+    /// processing fault with equal chance when it does.
+    fn next_fault(&mut self) -> Option<EngineError> {
+        if self.rng.next_f64() >= self.rate {
+            return None;
+        }
+        self.injected += 1;
+        let msg = "Injected fault".to_string();
+        if self.rng.next_u64() & 1 == 0 {
+            Some(EngineError::Deserialize(msg))
+        } else {
+            Some(EngineError::Processing(msg))
+        }
+    }
+}
+
+/// Optional behaviours of the engine, all of which are disabled by default.
+#[derive(Debug, Default, Clone)]
+struct EngineConfig {
+    // Deposits below this amount are rejected
+    min_deposit: Option<Decimal>,
+    // Deposits below this amount are rejected as dust, separately from the minimum deposit
+    min_meaningful_amount: Option<Decimal>,
+    // Held balances smaller than this are snapped to zero once disputes settle
+    held_dust_epsilon: Option<Decimal>,
+    // How records which fail while reading input are handled
+    error_policy: ErrorPolicy,
+    // How many of the most recent dispute messages are remembered to drop retransmits
+    dispute_dedup_window: usize,
+    // The number of decimal places each balance column is output with
+    output_scales: OutputScales,
+    // CSV output begins with a UTF-8 byte order mark
+    output_bom: bool,
+    // CSV output begins with a comment line giving the schema version
+    output_schema_version: bool,
+    // The capacity of the buffer account output is written through, the standard library's
+    // default when not set
+    output_buffer_size: Option<usize>,
+    // Locked accounts are output with zeroed balances
+    zeroed_locked_display: bool,
+    // Alternative names for transaction types accepted in the input
+    type_aliases: HashMap<String, TransactionType>,
+    // Accounts whose held to total ratio goes above this are flagged
+    max_held_ratio: Option<Decimal>,
+    // A systemic dispute event is flagged when the held to total ratio across all accounts goes
+    // above this
+    max_system_held_ratio: Option<Decimal>,
+    // Deposits taking an account's total above this are rejected
+    max_total_balance: Option<Decimal>,
+    // Which transactions are processed based on whether they are marked as sandbox data
+    data_mode: DataMode,
+    // How far below zero withdrawals may take an account's available balance
+    overdraft_limit: Decimal,
+    // The number of chargebacks after which an account is locked, one when not set
+    chargeback_lock_threshold: Option<usize>,
+    // Chargebacks reverse funds without ever locking the account
+    chargebacks_never_lock: bool,
+    // Deposits of zero are processed rather than rejected
+    allow_zero_deposits: bool,
+    // Transactions for clients not in this set are rejected
+    client_allowlist: Option<HashSet<u16>>,
+    // Resolves and chargebacks of transactions that aren't disputed are errors
+    strict_settlement: bool,
+    // How a resolve for more than the account holds is handled
+    resolve_shortfall_policy: ResolveShortfallPolicy,
+    // How a chargeback taking an account's total below zero is handled
+    negative_total_policy: NegativeTotalPolicy,
+    // How accounts with no activity are output
+    no_activity_output: NoActivityOutput,
+    // Every transaction given to the engine is kept for point in time queries
+    retain_history: bool,
+    // A warning is written for accounts whose displayed balances don't add up
+    display_mismatch_warnings: bool,
+    // The displayed total is the sum of the displayed available and held
+    reconciled_total_display: bool,
+    // Transactions reusing an idempotency key are dropped
+    idempotency_keys: bool,
+    // Client Id zero is reserved so its transactions are rejected
+    reject_zero_client_id: bool,
+    // Disputes of deposits larger than the available balance are rejected
+    reject_overdrawn_disputes: bool,
+    // Incoming client Ids are replaced by these before processing
+    client_id_map: HashMap<u16, u16>,
+    // The most disputes, resolves and chargebacks a single transaction can go through
+    max_dispute_actions: Option<usize>,
+    // The most disputes that can be open at once across every client
+    max_total_open_disputes: Option<usize>,
+    // Disputes, resolves and chargebacks out of order for their transaction are rejected
+    enforce_lifecycle: bool,
+    // Every transaction is rejected while the engine is globally frozen
+    reject_while_frozen: bool,
+    // An account which mirrors the net of every deposit and withdrawal as a control total
+    control_account: Option<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TransactionEngine<S: AccountStore = HashMap<u16, Account>> {
+    // The state of every account indexed by the account Id
+    accounts: S,
     // All transactions that have been seen that are currently eligible to be disputed indexed by
     // the transaction Id
-    transactions: HashMap<u32, Transaction>,
+    transactions: HashMap<TxId, Transaction>,
     // The set of transaction Ids that are currently in dispute
-    disputed_transactions: HashSet<u32>,
+    disputed_transactions: HashSet<TxId>,
+    // External metadata attached to accounts indexed by the account Id. This is kept separate
+    // from the balances so that `Account` can remain a cheap copyable value.
+    metadata: HashMap<u16, BTreeMap<String, String>>,
+    // The account Ids in the order the accounts were created
+    account_order: Vec<u16>,
+    // Errors kept under `ErrorPolicy::Collect` along with the 1-based input row they occurred on
+    errors: Vec<(usize, EngineError)>,
+    // How many records were dropped by the error policy
+    skipped_rows: usize,
+    // The most recent dispute, resolve and chargeback messages as (type, client Id, tx Id)
+    recent_dispute_messages: VecDeque<(TransactionType, u16, TxId)>,
+    // How many disputes, resolves and chargebacks were given an amount which was ignored
+    ignored_amounts: usize,
+    // How many resolves and chargebacks referred to a transaction that wasn't disputed
+    resolves_without_dispute: usize,
+    // The transactions whose last dispute was resolved, so a repeated resolve can be recognised
+    resolved_transactions: HashSet<TxId>,
+    // The transactions which have been charged back, which ends their dispute lifecycle
+    charged_back_transactions: HashSet<TxId>,
+    // How many resolves were repeats of one already applied
+    duplicate_resolves: usize,
+    // The accounts locked by `freeze_all` while the engine is globally frozen
+    global_freeze: Option<HashSet<u16>>,
+    // The clients who have had a deposit or withdrawal applied
+    funded_clients: HashSet<u16>,
+    // The clients whose held to total ratio has gone above the configured maximum
+    held_ratio_flagged: HashSet<u16>,
+    // Whether held across all accounts has gone above the configured fraction of their total
+    systemic_dispute_flagged: bool,
+    // The clients who have had a deposit disputed after spending it
+    overdrawn_disputes: HashSet<u16>,
+    // The clients who have had any transaction disputed, however the dispute ended
+    ever_disputed: HashSet<u16>,
+    // The clients whose total was taken below zero by a chargeback, when these are flagged
+    negative_total_chargebacks: HashSet<u16>,
+    // The number of chargebacks processed for each client
+    chargeback_counts: HashMap<u16, usize>,
+    // The deposits applied for each client and how much of them was charged back
+    deposit_totals: HashMap<u16, DepositTotals>,
+    // The withdrawals attempted by each client and how many of them were applied
+    withdrawal_counts: HashMap<u16, WithdrawalCounts>,
+    // The number of disputes, resolves and chargebacks each transaction has gone through, only
+    // counted when they are capped
+    dispute_actions: HashMap<TxId, usize>,
+    // The chargebacks which locked accounts in the order they happened
+    lock_records: Vec<LockRecord>,
+    // The Ids of deposits and withdrawals seen so far when duplicate checking is enabled
+    duplicate_filter: Option<BloomFilter>,
+    // The idempotency keys seen so far when they are checked
+    idempotency_keys: HashSet<String>,
+    // Injects faults into records read from input for resilience testing
+    fault_injector: Option<FaultInjector>,
+    // Every transaction given to the engine for each client in order, when history is retained
+    history: HashMap<u16, Vec<Transaction>>,
+    // The number of transactions given to the engine so far, used to order the write-ahead log
+    sequence: u64,
+    // The steps taken while processing, only recorded while producing an explanation
+    trace: Option<Vec<String>>,
+    // Every failure with its input row, only recorded while producing a validation report
+    validation_issues: Option<Vec<(usize, EngineError)>>,
+    // Where balance changes are sent as they are applied, if anywhere
+    event_sender: Option<Sender<BalanceEvent>>,
+    config: EngineConfig,
+}
+
+impl Default for TransactionEngine {
+    fn default() -> Self {
+        Self::with_account_store(HashMap::new())
+    }
 }
 
 impl TransactionEngine {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restores an engine from an account CSV in the same format the engine outputs, so that a
+    /// previous run's output can be used as the baseline for processing further transactions.
+    /// Only balances and locked states are restored, transactions from the previous run can't be
+    /// disputed. Restored locked accounts reject further transactions until an `unlock`.
+    pub fn from_accounts_csv<R: Read>(reader: R) -> anyhow::Result<Self> {
+        let mut engine = Self::new();
+        // Skip any schema version comment
+        let mut rdr = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .from_reader(reader);
+        for row_res in rdr.deserialize::<AccountRow>() {
+            let row = row_res.context("Failed to deserialize account record")?;
+            let (id, account) = row.into_account()?;
+            if engine.accounts.insert(id, account).is_none() {
+                engine.account_order.push(id);
+            }
+        }
+        Ok(engine)
+    }
+
+    /// Processes the input CSV with a default engine and checks the resulting accounts against an
+    /// expected account CSV, as a testing or CI utility. Accounts are compared as they are output
+    /// so the expected CSV only needs the output precision. The inner result holds the difference
+    /// (actual minus expected) for every client that doesn't match, sorted by Id, a client missing
+    /// from either side is treated as an empty account. The outer result fails if either source
+    /// can't be read.
+    pub fn verify_against_expected<R1: Read, R2: Read>(
+        input: R1,
+        expected_accounts: R2,
+    ) -> anyhow::Result<Result<(), Vec<AccountDiff>>> {
+        let mut engine = Self::new();
+        engine.process_reader(input)?;
+        let mut output = Vec::new();
+        engine.write_accounts(&mut output)?;
+        let actual = Self::from_accounts_csv(output.as_slice())?.accounts;
+        let expected = Self::from_accounts_csv(expected_accounts)?.accounts;
+        let diffs = account_diffs(&expected, &actual);
+        if diffs.is_empty() {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(diffs))
+        }
+    }
+}
+
+impl<S: AccountStore> TransactionEngine<S> {
+    /// Creates an engine which keeps its accounts in the given store rather than the default
+    /// `HashMap`. The store may already hold accounts, which are processed into like any other.
+    pub fn with_account_store(store: S) -> Self {
+        let mut account_order: Vec<u16> = store.iter().map(|(id, _)| id).collect();
+        account_order.sort_unstable();
         Self {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-            disputed_transactions: HashSet::new(),
+            accounts: store,
+            transactions: Default::default(),
+            disputed_transactions: Default::default(),
+            metadata: Default::default(),
+            account_order,
+            errors: Default::default(),
+            skipped_rows: Default::default(),
+            recent_dispute_messages: Default::default(),
+            ignored_amounts: Default::default(),
+            resolves_without_dispute: Default::default(),
+            resolved_transactions: Default::default(),
+            charged_back_transactions: Default::default(),
+            duplicate_resolves: Default::default(),
+            global_freeze: Default::default(),
+            funded_clients: Default::default(),
+            held_ratio_flagged: Default::default(),
+            systemic_dispute_flagged: Default::default(),
+            overdrawn_disputes: Default::default(),
+            ever_disputed: Default::default(),
+            negative_total_chargebacks: Default::default(),
+            chargeback_counts: Default::default(),
+            deposit_totals: Default::default(),
+            withdrawal_counts: Default::default(),
+            dispute_actions: Default::default(),
+            lock_records: Default::default(),
+            duplicate_filter: Default::default(),
+            idempotency_keys: Default::default(),
+            fault_injector: Default::default(),
+            history: Default::default(),
+            sequence: Default::default(),
+            trace: Default::default(),
+            validation_issues: Default::default(),
+            event_sender: Default::default(),
+            config: Default::default(),
         }
     }
 
-    /// Processes the given transaction creating & updating the client's account as necessary.
-    pub fn process_transaction(&mut self, tx: Transaction) -> anyhow::Result<()> {
-        // If this is the first transaction for the client create an account and insert that
-        // otherwise get the existing account
-        let tx_account = self
-            .accounts
-            .entry(tx.client_id)
-            .or_insert_with(Account::default);
+    /// Deposits of zero move no funds so are rejected with a `ZeroDeposit` error by default, this
+    /// processes them like any other deposit instead. Disputes, resolves and chargebacks never
+    /// need an amount so are unaffected.
+    pub fn with_zero_deposits_allowed(mut self, allowed: bool) -> Self {
+        self.config.allow_zero_deposits = allowed;
+        self
+    }
 
-        // If the account is locked we won't do any further processing
-        if tx_account.locked {
-            // It may be better to treat this as an error case
-            return anyhow::Result::Ok(());
+    /// Takes a copy of the engine's state which `recover` can restore, along with the sequence
+    /// number of the last transaction processed so a write-ahead log can be replayed from there.
+    pub fn snapshot(&self) -> EngineSnapshot {
+        EngineSnapshot {
+            sequence: self.sequence,
+            accounts: self.accounts.iter().collect(),
+            transactions: self.transactions.clone(),
+            disputed_transactions: self.disputed_transactions.clone(),
+            metadata: self.metadata.clone(),
+            account_order: self.account_order.clone(),
+            recent_dispute_messages: self.recent_dispute_messages.clone(),
+            ignored_amounts: self.ignored_amounts,
+            resolves_without_dispute: self.resolves_without_dispute,
+            resolved_transactions: self.resolved_transactions.clone(),
+            charged_back_transactions: self.charged_back_transactions.clone(),
+            duplicate_resolves: self.duplicate_resolves,
+            global_freeze: self.global_freeze.clone(),
+            funded_clients: self.funded_clients.clone(),
+            held_ratio_flagged: self.held_ratio_flagged.clone(),
+            systemic_dispute_flagged: self.systemic_dispute_flagged,
+            overdrawn_disputes: self.overdrawn_disputes.clone(),
+            ever_disputed: self.ever_disputed.clone(),
+            negative_total_chargebacks: self.negative_total_chargebacks.clone(),
+            chargeback_counts: self.chargeback_counts.clone(),
+            deposit_totals: self.deposit_totals.clone(),
+            withdrawal_counts: self.withdrawal_counts.clone(),
+            dispute_actions: self.dispute_actions.clone(),
+            lock_records: self.lock_records.clone(),
+            duplicate_filter: self.duplicate_filter.clone(),
+            idempotency_keys: self.idempotency_keys.clone(),
+            history: self.history.clone(),
         }
+    }
 
-        // Take appropriate action based on the transaction type
-        match tx.tx_type {
-            TransactionType::Deposit => {
-                let tx_amount = tx.amount().context("Failed to get deposit amount")?;
-                tx_account.total += tx_amount;
-                tx_account.available += tx_amount;
-                // Store this transaction in case of later dispute
-                self.transactions.insert(tx.tx_id, tx);
+    /// Copies out the accounts, the transactions eligible to be disputed and the set of disputed
+    /// transactions for inspection while debugging. Use `snapshot` to save state for restoring.
+    pub fn debug_state(&self) -> DebugState {
+        DebugState {
+            accounts: self
+                .accounts
+                .iter()
+                .map(|(id, account)| {
+                    let account = DebugAccount {
+                        available: account.available,
+                        held: account.held,
+                        total: account.total,
+                        locked: account.locked,
+                    };
+                    (id, account)
+                })
+                .collect(),
+            transactions: self
+                .transactions
+                .iter()
+                .map(|(tx_id, tx)| {
+                    let tx = DebugTransaction {
+                        tx_type: tx.tx_type,
+                        client: tx.client_id,
+                        amount: tx.amount.clone(),
+                    };
+                    (*tx_id, tx)
+                })
+                .collect(),
+            disputed: self.disputed_transactions.iter().copied().collect(),
+        }
+    }
+
+    /// Takes a snapshot like `snapshot` and writes it in the given format, to be read back with
+    /// `EngineSnapshot::read_from` and restored with `recover`.
+    pub fn snapshot_to<W: Write>(&self, writer: W, format: SnapshotFormat) -> anyhow::Result<()> {
+        let snapshot = self.snapshot();
+        match format {
+            SnapshotFormat::Json => {
+                serde_json::to_writer(writer, &snapshot).context("Failed to write JSON snapshot")
             }
-            TransactionType::Withdrawal => {
-                let tx_amount = tx.amount().context("Failed to get withdrawal amount")?;
-                // Only process this withdrawal if the account has sufficient available funds
-                if tx_account.available >= tx_amount {
-                    tx_account.total -= tx_amount;
-                    tx_account.available -= tx_amount;
-                    // Store this transaction in case of later dispute
-                    self.transactions.insert(tx.tx_id, tx);
+            SnapshotFormat::Bincode => bincode::serialize_into(writer, &snapshot)
+                .context("Failed to write bincode snapshot"),
+        }
+    }
+
+    /// The difference (now minus then) for every client whose account has changed since the
+    /// snapshot was taken, sorted by Id, for bringing a replica restored from the snapshot up to
+    /// date with `apply_diffs`.
+    pub fn delta_since(&self, snapshot: &EngineSnapshot) -> Vec<AccountDiff> {
+        let current: HashMap<u16, Account> = self.accounts.iter().collect();
+        account_diffs(&snapshot.accounts, &current)
+    }
+
+    /// Applies account differences produced by `delta_since` on another engine, creating any
+    /// accounts that don't exist yet. Only balances and locked states are synced, so transactions
+    /// processed since the snapshot can't be disputed on this engine.
+    pub fn apply_diffs(&mut self, diffs: &[AccountDiff]) {
+        for diff in diffs {
+            let mut account = match self.accounts.get(diff.id) {
+                Some(account) => account,
+                None => {
+                    self.account_order.push(diff.id);
+                    Account::default()
                 }
+            };
+            account.available += diff.available;
+            account.held += diff.held;
+            account.total += diff.total;
+            if let Some(locked) = diff.locked {
+                account.locked = locked;
             }
-            TransactionType::Dispute => {
-                // Only dispute this transaction if the transaction Id refers to a valid transaction
-                if let Some(disputed_tx) = self.transactions.get(&tx.tx_id) {
-                    let disputed_tx_amount = disputed_tx
-                        .amount()
-                        .context("Failed to get disputed transaction amount")?;
-                    match disputed_tx.tx_type {
-                        TransactionType::Deposit => {
-                            tx_account.available -= disputed_tx_amount;
-                            tx_account.held += disputed_tx_amount;
-                        }
-                        TransactionType::Withdrawal => {
-                            tx_account.total += disputed_tx_amount;
-                            tx_account.held += disputed_tx_amount;
-                        }
-                        _ => return Err(Error::msg("Invalid disputed transaction")),
-                    }
-                    self.disputed_transactions.insert(disputed_tx.tx_id);
-                }
+            self.accounts.insert(diff.id, account);
+        }
+    }
+
+    /// Restores the state in `snapshot` into this engine, keeping its configuration, then replays
+    /// every entry of the write-ahead log written by `process_transaction_logged` that comes after
+    /// the snapshot, bringing the engine back to its state at the time of a crash. Entries the
+    /// snapshot already covers are skipped. Transactions that failed when first processed had no
+    /// effect and fail the same way again, so their errors are dropped. A final line that can't be
+    /// parsed is taken to be a write torn by the crash and ignored, while a bad line anywhere else
+    /// is an error.
+    pub fn recover<R: Read>(mut self, snapshot: EngineSnapshot, wal: R) -> anyhow::Result<Self> {
+        self.sequence = snapshot.sequence;
+        self.accounts = S::default();
+        for (id, account) in snapshot.accounts {
+            self.accounts.insert(id, account);
+        }
+        self.transactions = snapshot.transactions;
+        self.disputed_transactions = snapshot.disputed_transactions;
+        self.metadata = snapshot.metadata;
+        self.account_order = snapshot.account_order;
+        self.recent_dispute_messages = snapshot.recent_dispute_messages;
+        self.ignored_amounts = snapshot.ignored_amounts;
+        self.resolves_without_dispute = snapshot.resolves_without_dispute;
+        self.resolved_transactions = snapshot.resolved_transactions;
+        self.charged_back_transactions = snapshot.charged_back_transactions;
+        self.duplicate_resolves = snapshot.duplicate_resolves;
+        self.global_freeze = snapshot.global_freeze;
+        self.funded_clients = snapshot.funded_clients;
+        self.held_ratio_flagged = snapshot.held_ratio_flagged;
+        self.systemic_dispute_flagged = snapshot.systemic_dispute_flagged;
+        self.overdrawn_disputes = snapshot.overdrawn_disputes;
+        self.ever_disputed = snapshot.ever_disputed;
+        self.negative_total_chargebacks = snapshot.negative_total_chargebacks;
+        self.chargeback_counts = snapshot.chargeback_counts;
+        self.deposit_totals = snapshot.deposit_totals;
+        self.withdrawal_counts = snapshot.withdrawal_counts;
+        self.dispute_actions = snapshot.dispute_actions;
+        self.lock_records = snapshot.lock_records;
+        self.duplicate_filter = snapshot.duplicate_filter;
+        self.idempotency_keys = snapshot.idempotency_keys;
+        self.history = snapshot.history;
+
+        let mut lines = BufReader::new(wal).lines().peekable();
+        while let Some(line) = lines.next() {
+            let line = line.context("Failed to read WAL")?;
+            let entry = match serde_json::from_str::<WalEntry>(&line) {
+                Ok(entry) => entry,
+                Err(_) if lines.peek().is_none() => break,
+                Err(err) => return Err(err).context("Failed to parse WAL entry"),
+            };
+            if entry.seq <= self.sequence {
+                continue;
             }
-            TransactionType::Resolve => {
-                // The transaction must both refer to a valid existing transaction and that
-                // transaction must be currently disputed in order for us to process a resolve
-                if let Some(disputed_tx) = self.transactions.get(&tx.tx_id) {
-                    if self.disputed_transactions.contains(&tx.tx_id) {
-                        let disputed_tx_amount = disputed_tx
-                            .amount()
-                            .context("Failed to get disputed transaction amount")?;
-                        match disputed_tx.tx_type {
-                            TransactionType::Deposit => {
-                                tx_account.held -= disputed_tx_amount;
-                                tx_account.available += disputed_tx_amount;
-                            }
-                            TransactionType::Withdrawal => {
-                                tx_account.total -= disputed_tx_amount;
-                                tx_account.held -= disputed_tx_amount;
-                            }
-                            _ => return Err(Error::msg("Invalid disputed transaction")),
-                        }
-                        // Now that we have processed the resolve we can mark the transaction as no
-                        // longer disputed
-                        self.disputed_transactions.remove(&tx.tx_id);
-                    }
-                }
+            // Any gap means the log doesn't line up with the snapshot
+            if entry.seq != self.sequence + 1 {
+                return Err(Error::msg(format!(
+                    "WAL entry {} doesn't follow sequence {}",
+                    entry.seq, self.sequence
+                )));
             }
-            TransactionType::Chargeback => {
-                // The transaction must both refer to a valid existing transaction and that
-                // transaction must be currently disputed in order for us to process a chargeback
-                if let Some(disputed_tx) = self.transactions.get(&tx.tx_id) {
-                    if self.disputed_transactions.contains(&tx.tx_id) {
-                        let disputed_tx_amount = disputed_tx
-                            .amount()
-                            .context("Failed to get disputed transaction amount")?;
-                        match disputed_tx.tx_type {
-                            TransactionType::Deposit => {
-                                tx_account.held -= disputed_tx_amount;
-                                tx_account.total -= disputed_tx_amount;
-                            }
-                            TransactionType::Withdrawal => {
-                                tx_account.held -= disputed_tx_amount;
-                                tx_account.available += disputed_tx_amount;
-                            }
-                            _ => return Err(Error::msg("Invalid disputed transaction")),
+            let _ = self.process(entry.tx);
+        }
+        Ok(self)
+    }
+
+    /// Rejects any transaction for a client that isn't on the allowlist read from `reader` with a
+    /// `ClientNotAllowed` error, for environments with a fixed roster of clients. The allowlist
+    /// has one client Id per line, blank lines and lines starting with `#` are skipped.
+    pub fn with_client_allowlist<R: Read>(mut self, reader: R) -> anyhow::Result<Self> {
+        let mut allowlist = HashSet::new();
+        for (idx, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line.context("Failed to read client allowlist")?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let client_id = line
+                .parse::<u16>()
+                .with_context(|| format!("Invalid client Id on line {} of allowlist", idx + 1))?;
+            allowlist.insert(client_id);
+        }
+        self.config.client_allowlist = Some(allowlist);
+        Ok(self)
+    }
+
+    /// Rejects resolves and chargebacks of transactions that aren't currently disputed with a
+    /// `ResolveWithoutDispute` error. By default they are ignored, either way they are counted by
+    /// `resolves_without_dispute`.
+    pub fn with_strict_settlement(mut self, strict: bool) -> Self {
+        self.config.strict_settlement = strict;
+        self
+    }
+
+    /// Sets how a resolve for more than the account holds is handled, guarding the invariant that
+    /// an account's available balance never exceeds its total. By default the full disputed
+    /// amount is released regardless.
+    pub fn with_resolve_shortfall_policy(mut self, policy: ResolveShortfallPolicy) -> Self {
+        self.config.resolve_shortfall_policy = policy;
+        self
+    }
+
+    /// Sets how accounts with no activity, such as those only ever sent disputes, are output. By
+    /// default they are output like any other account.
+    pub fn with_no_activity_output(mut self, output: NoActivityOutput) -> Self {
+        self.config.no_activity_output = output;
+        self
+    }
+
+    /// Sets how a chargeback that would take an account's total below zero is handled. By default
+    /// it is applied, leaving the total negative.
+    pub fn with_negative_total_policy(mut self, policy: NegativeTotalPolicy) -> Self {
+        self.config.negative_total_policy = policy;
+        self
+    }
+
+    /// Rejects deposits smaller than `amount` as dust with a `DustDeposit` error, so floods of
+    /// micro-deposits can't inflate the transactions kept for disputes. This is independent of
+    /// `with_min_deposit`, which is a business rule rather than spam protection, and is checked
+    /// before it.
+    pub fn with_min_meaningful_amount(mut self, amount: Decimal) -> Self {
+        self.config.min_meaningful_amount = Some(amount);
+        self
+    }
+
+    /// Keeps every transaction given to the engine so that `balance_at_tx` can answer point in
+    /// time queries. The history grows with the input so this is off by default.
+    pub fn with_history(mut self, retain_history: bool) -> Self {
+        self.config.retain_history = retain_history;
+        self
+    }
+
+    /// Designates an observer-only control account which accumulates the net of every deposit
+    /// minus every withdrawal applied across all clients, as a running control total of system
+    /// flows. Disputes, resolves and chargebacks don't touch it, and `undo_file` reverses it along
+    /// with the transactions it undoes. The control account is output like any other account but
+    /// left out of the aggregates and reports over client accounts, and transactions addressed to
+    /// it are rejected with a `ClientNotAllowed` error.
+    pub fn with_control_account(mut self, client_id: u16) -> Self {
+        self.config.control_account = Some(client_id);
+        self
+    }
+
+    /// Rejects every transaction for client Id zero with a `ClientNotAllowed` error, for systems
+    /// that reserve that Id.
+    pub fn with_reject_zero_client_id(mut self, reject: bool) -> Self {
+        self.config.reject_zero_client_id = reject;
+        self
+    }
+
+    /// Rejects disputes of deposits for more than the client's available balance, which happens
+    /// when the deposit has since been withdrawn, with a `DisputeExceedsAvailable` error. By default
+    /// these disputes are applied, leaving held above total and available negative, and the client
+    /// is flagged in `overdrawn_disputes`.
+    pub fn with_reject_overdrawn_disputes(mut self, reject: bool) -> Self {
+        self.config.reject_overdrawn_disputes = reject;
+        self
+    }
+
+    /// Rejects any deposit smaller than `min_deposit` with a `BelowMinimumDeposit` error. The
+    /// rejected deposit has no effect on the account.
+    pub fn with_min_deposit(mut self, min_deposit: Decimal) -> Self {
+        self.config.min_deposit = Some(min_deposit);
+        self
+    }
+
+    /// Rejects any deposit that would take an account's total balance above `max_total_balance`
+    /// with an `AboveMaximumBalance` error. The rejected deposit has no effect on the account.
+    pub fn with_max_total_balance(mut self, max_total_balance: Decimal) -> Self {
+        self.config.max_total_balance = Some(max_total_balance);
+        self
+    }
+
+    /// After a resolve or chargeback, any held balance smaller than `epsilon` that isn't backed by
+    /// an open dispute is released back to available, so sub-unit dust can't linger as a phantom
+    /// held balance.
+    pub fn with_held_dust_epsilon(mut self, epsilon: Decimal) -> Self {
+        self.config.held_dust_epsilon = Some(epsilon);
+        self
+    }
+
+    /// Allows withdrawals to take an account's available balance below zero by up to `limit`. By
+    /// default there is no overdraft and withdrawals need sufficient available funds.
+    pub fn with_overdraft_limit(mut self, limit: Decimal) -> Self {
+        self.config.overdraft_limit = limit;
+        self
+    }
+
+    /// Escalates to locking an account only once its client has had `threshold` chargebacks,
+    /// across any of their transactions, instead of on the first chargeback. A threshold of zero
+    /// is treated as one, which is the default.
+    pub fn with_chargeback_lock_threshold(mut self, threshold: usize) -> Self {
+        self.config.chargeback_lock_threshold = Some(threshold.max(1));
+        self
+    }
+
+    /// Sets whether chargebacks lock the client's account, which they do by default. Without
+    /// locking a chargeback still reverses the disputed funds but the account stays active and
+    /// keeps accepting transactions, as some jurisdictions require.
+    pub fn with_lock_on_chargeback(mut self, lock: bool) -> Self {
+        self.config.chargebacks_never_lock = !lock;
+        self
+    }
+
+    /// Sends a `BalanceEvent` to the given channel for every transaction that changes an account,
+    /// so a consumer such as another thread can react to balance changes as they happen. Events
+    /// are no longer sent once the receiver is dropped. Forks of the engine don't send events.
+    pub fn with_event_channel(mut self, sender: Sender<BalanceEvent>) -> Self {
+        self.event_sender = Some(sender);
+        self
+    }
+
+    /// Drops any transaction whose optional `idempotency_key` column repeats the key of an earlier
+    /// transaction, whatever their transaction Ids. Transactions without a key are never dropped.
+    /// A key only counts as seen once its transaction has been applied, so a transaction that
+    /// failed or was ignored can be retried under the same key.
+    pub fn with_idempotency_keys(mut self, check: bool) -> Self {
+        self.config.idempotency_keys = check;
+        self
+    }
+
+    /// For resilience testing, fails roughly `rate` (0 to 1) of the records read by
+    /// `process_reader` with an injected deserialization or processing fault in place of the
+    /// record, so callers can check their `ErrorPolicy` copes. The faults are chosen by a
+    /// generator seeded with `seed` so a run can be reproduced exactly. Fails if `rate` isn't
+    /// between zero and one.
+    pub fn with_fault_injection(mut self, rate: f64, seed: u64) -> anyhow::Result<Self> {
+        if !(0.0..=1.0).contains(&rate) {
+            return Err(Error::msg(format!(
+                "Fault injection rate must be between 0 and 1, not {}",
+                rate
+            )));
+        }
+        self.fault_injector = Some(FaultInjector {
+            rng: SplitMix64::new(seed),
+            rate,
+            injected: 0,
+        });
+        Ok(self)
+    }
+
+    /// Caps how many disputes, resolves and chargebacks any single transaction can go through,
+    /// guarding against pathological chains of disputes on one transaction. Actions beyond the
+    /// cap fail with a `DisputeActionLimit` error and have no effect.
+    pub fn with_max_dispute_actions(mut self, max_actions: usize) -> Self {
+        self.config.max_dispute_actions = Some(max_actions);
+        self
+    }
+
+    /// Rejects disputes, resolves and chargebacks that are out of order for the transaction they
+    /// refer to with a `LifecycleViolation` error. A transaction can only be disputed by its own
+    /// client while it isn't already disputed, resolved or charged back only while disputed, and
+    /// nothing more once charged back. Repeated resolves are still dropped as duplicates. By
+    /// default out of order actions are ignored, or rejected under strict settlement only when
+    /// settling a transaction that isn't disputed.
+    pub fn with_lifecycle_enforcement(mut self, enforce: bool) -> Self {
+        self.config.enforce_lifecycle = enforce;
+        self
+    }
+
+    /// Sets whether every transaction is rejected with a `GloballyFrozen` error while the engine
+    /// is frozen by `freeze_all`, including those for new clients and unlocks. Otherwise a freeze
+    /// only locks the accounts that exist when it is made. This is off by default.
+    pub fn with_reject_while_frozen(mut self, reject: bool) -> Self {
+        self.config.reject_while_frozen = reject;
+        self
+    }
+
+    /// Caps how many disputes can be open at once across the whole engine, guarding against a
+    /// flood of disputes. New disputes beyond the cap fail with an `OpenDisputeLimit` error and
+    /// have no effect until an open dispute is resolved or charged back.
+    pub fn with_max_total_open_disputes(mut self, max_open: usize) -> Self {
+        self.config.max_total_open_disputes = Some(max_open);
+        self
+    }
+
+    /// Sets whether transactions marked as sandbox data are processed. By default every
+    /// transaction is processed, `DataMode::Production` ignores sandbox transactions and
+    /// `DataMode::Sandbox` ignores everything else. Ignored transactions don't create accounts.
+    pub fn with_data_mode(mut self, data_mode: DataMode) -> Self {
+        self.config.data_mode = data_mode;
+        self
+    }
+
+    /// Sets how records which fail to deserialize or process are handled by `process_reader`. By
+    /// default processing is aborted on the first failure.
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.config.error_policy = error_policy;
+        self
+    }
+
+    /// Remembers the last `window` applied dispute, resolve and chargeback messages and drops any
+    /// message identical to one of them, so retransmitted notifications are handled idempotently.
+    /// This covers cases the dispute state alone can't, such as a dispute retransmitted after the
+    /// original dispute was already resolved.
+    pub fn with_dispute_dedup_window(mut self, window: usize) -> Self {
+        self.config.dispute_dedup_window = window;
+        self
+    }
+
+    /// Sets the number of decimal places each balance column is output with. Note that mixing
+    /// scales can make the displayed available and held no longer add up to the displayed total,
+    /// `reconcile` reports any accounts where this happens.
+    pub fn with_output_scales(mut self, output_scales: OutputScales) -> Self {
+        self.config.output_scales = output_scales;
+        self
+    }
+
+    /// Sets whether CSV output begins with a UTF-8 byte order mark so that Excel opens it with the
+    /// right encoding. This is off by default.
+    pub fn with_output_bom(mut self, bom: bool) -> Self {
+        self.config.output_bom = bom;
+        self
+    }
+
+    /// Sets whether CSV output begins with a `# schema: v1` comment line ahead of the header, so
+    /// downstream consumers can detect changes to the format. This is off by default.
+    pub fn with_output_schema_version(mut self, schema_version: bool) -> Self {
+        self.config.output_schema_version = schema_version;
+        self
+    }
+
+    /// Sets the capacity in bytes of the buffer accounts are written through, larger buffers
+    /// making fewer writes to the underlying writer when there are many accounts.
+    pub fn with_output_buffer_size(mut self, capacity: usize) -> Self {
+        self.config.output_buffer_size = Some(capacity);
+        self
+    }
+
+    /// Displays each account's total as the sum of its available and held balances as displayed,
+    /// rather than its real total rounded, so the columns always add up on paper. Totals are only
+    /// affected when rounding or high-precision amounts would make them disagree, and sums are
+    /// exact as long as the total's output scale is at least the others'. This only affects
+    /// output, the real balances are kept and returned by `account`.
+    pub fn with_reconciled_total_display(mut self, reconciled: bool) -> Self {
+        self.config.reconciled_total_display = reconciled;
+        self
+    }
+
+    /// Writes a warning to the warnings writer given to `write_accounts_with_warnings`,
+    /// `write_selected_accounts_with_warnings` or `process_reader_grouped` for any account whose
+    /// available and held balances as displayed don't sum to its total as displayed, which output
+    /// scales or rounding can cause. This is the same check `reconcile` makes.
+    pub fn with_display_mismatch_warnings(mut self, warn: bool) -> Self {
+        self.config.display_mismatch_warnings = warn;
+        self
+    }
+
+    /// Flags any account whose held balance goes above `max_ratio` of its total balance, which
+    /// indicates heavy exposure to disputes. Flagged accounts stay flagged even once their disputes
+    /// settle and can be retrieved with `held_ratio_flagged`.
+    pub fn with_max_held_ratio(mut self, max_ratio: Decimal) -> Self {
+        self.config.max_held_ratio = Some(max_ratio);
+        self
+    }
+
+    /// Flags a systemic dispute event if the held balance summed across all accounts goes above
+    /// `max_ratio` of the total balance summed across all accounts. Like the per account flag this
+    /// stays set once raised and can be checked with `systemic_dispute_flagged`. Checking this
+    /// sums every account after each transaction so it slows processing with many clients.
+    pub fn with_max_system_held_ratio(mut self, max_ratio: Decimal) -> Self {
+        self.config.max_system_held_ratio = Some(max_ratio);
+        self
+    }
+
+    /// Outputs locked accounts with all their balances shown as zero, as their funds are frozen.
+    /// This only affects output, the real balances are kept and returned by `account`.
+    pub fn with_zeroed_locked_display(mut self, zeroed_locked_display: bool) -> Self {
+        self.config.zeroed_locked_display = zeroed_locked_display;
+        self
+    }
+
+    /// Rejects deposits and withdrawals which reuse the Id of an earlier applied deposit or
+    /// withdrawal with a `DuplicateTransaction` error. Seen Ids are tracked in a bloom filter sized
+    /// for `expected_transactions`, so the check itself takes fixed memory however many Ids are
+    /// seen, at the cost of wrongly rejecting roughly `false_positive_rate` of legitimate
+    /// transactions. Deposits and withdrawals are still stored in full so they can be disputed.
+    /// Fails if `false_positive_rate` isn't strictly between zero and one.
+    pub fn with_duplicate_filter(
+        mut self,
+        expected_transactions: usize,
+        false_positive_rate: f64,
+    ) -> anyhow::Result<Self> {
+        if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            return Err(Error::msg(format!(
+                "False positive rate must be between 0 and 1, not {}",
+                false_positive_rate
+            )));
+        }
+        self.duplicate_filter = Some(BloomFilter::new(expected_transactions, false_positive_rate));
+        Ok(self)
+    }
+
+    /// Accepts `alias` in the input's `type` column as another name for `tx_type`, e.g. `credit`
+    /// for deposits. The canonical type names are always accepted.
+    pub fn with_type_alias(mut self, alias: &str, tx_type: TransactionType) -> Self {
+        self.config.type_aliases.insert(alias.to_string(), tx_type);
+        self
+    }
+
+    /// Replaces the client Id of every incoming transaction found in `client_id_map` with the Id
+    /// it maps to before anything else is done with it, so several source Ids can be merged into
+    /// one account or Ids anonymized. Ids not in the map are left as they are.
+    pub fn with_client_id_map(mut self, client_id_map: HashMap<u16, u16>) -> Self {
+        self.config.client_id_map = client_id_map;
+        self
+    }
+
+    /// Attaches a piece of external metadata to the given client's account, replacing any
+    /// previous value for the same key. Metadata is only emitted alongside an account once that
+    /// account has been created by a transaction.
+    pub fn set_account_metadata(&mut self, client_id: u16, key: &str, value: String) {
+        self.metadata
+            .entry(client_id)
+            .or_default()
+            .insert(key.to_string(), value);
+    }
+
+    /// Retrieves a piece of external metadata previously attached to the given client's account.
+    pub fn account_metadata(&self, client_id: u16, key: &str) -> Option<&str> {
+        self.metadata
+            .get(&client_id)
+            .and_then(|metadata| metadata.get(key))
+            .map(String::as_str)
+    }
+
+    /// Processes every transaction in the given CSV source in order, returning the number of
+    /// records read including any dropped by the error policy. The source must begin with a
+    /// header row, a source containing only the header is valid and simply has no transactions.
+    /// All state is kept between calls, so for two-phase feeds disputes read from one source can
+    /// refer to deposits and withdrawals read from an earlier one.
+    pub fn process_reader<R: Read>(&mut self, reader: R) -> anyhow::Result<usize> {
+        let mut processor = self.processor(reader)?;
+        processor.run()?;
+        Ok(processor.rows())
+    }
+
+    /// Processes every transaction in the given CSV source like `process_reader`, writing each
+    /// client's account to `writer` as soon as a transaction for a different client appears rather
+    /// than once the whole source has been processed. This assumes the input is grouped by client,
+    /// with all of a client's transactions next to each other. A client whose transactions are
+    /// split across the input is written once for each group, each row showing the account as it
+    /// was at the end of that group. Any display mismatch warnings are written to `warnings`.
+    /// Returns the number of records read.
+    pub fn process_reader_grouped<R: Read, W: Write, E: Write>(
+        &mut self,
+        reader: R,
+        mut writer: W,
+        mut warnings: E,
+    ) -> anyhow::Result<usize> {
+        // The input header is checked before anything is written
+        let mut processor = self.processor(reader)?;
+        let header = processor.engine.accounts_header();
+        processor.engine.write_header(&mut writer, &header)?;
+        let mut current_client = None;
+        while processor.step()? {
+            let last_client = processor.last_client;
+            if last_client.is_some() && last_client != current_client {
+                if let Some(client_id) = current_client {
+                    processor
+                        .engine
+                        .write_client(&mut writer, client_id, &mut warnings)?;
+                }
+                current_client = last_client;
+            }
+        }
+        if let Some(client_id) = current_client {
+            processor
+                .engine
+                .write_client(&mut writer, client_id, &mut warnings)?;
+        }
+        let rows = processor.rows();
+        writer.flush().context("Failed to flush accounts")?;
+        Ok(rows)
+    }
+
+    /// Writes the client's account row if they have an account.
+    fn write_client<W: Write, E: Write>(
+        &self,
+        writer: W,
+        client_id: u16,
+        warnings: E,
+    ) -> anyhow::Result<()> {
+        match self.account(client_id) {
+            Some(account) => self.write_row(writer, &account, warnings),
+            None => Ok(()),
+        }
+    }
+
+    /// Reverses the effect on balances of the deposits and withdrawals in a CSV source that was
+    /// previously processed, for rolling back a whole file in batch pipelines. Only transactions
+    /// the engine still holds for the same client are reversed, so ones that failed or were
+    /// ignored when first processed are skipped, and reversed transactions can no longer be
+    /// disputed. Disputes, resolves and chargebacks in the source aren't undone, and deposits and
+    /// withdrawals that are currently disputed are left in place as their funds are held. The
+    /// source is read the same way as by `process_reader` and reversed from the last transaction
+    /// to the first, and reversals on locked accounts or
+    /// that would overdraw the account fail with an `UndoRefused` error; failures are handled by
+    /// the error policy. Returns the number of transactions reversed.
+    pub fn undo_file<R: Read>(&mut self, reader: R) -> anyhow::Result<usize> {
+        self.processor(reader)?.undo_all()
+    }
+
+    /// Reverses a single deposit or withdrawal for `undo_file`, returning whether there was
+    /// anything to reverse.
+    fn undo_transaction(&mut self, tx: &Transaction) -> anyhow::Result<bool> {
+        let client_map = &self.config.client_id_map;
+        let client_id = *client_map.get(&tx.client_id).unwrap_or(&tx.client_id);
+        let applied = match self.transactions.get(&tx.tx_id) {
+            Some(applied) => applied,
+            None => return Ok(false),
+        };
+        if applied.client_id != client_id
+            || applied.tx_type != tx.tx_type
+            || self.disputed_transactions.contains(&tx.tx_id)
+        {
+            return Ok(false);
+        }
+        let amount = applied
+            .amount()
+            .context("Failed to get transaction amount")?;
+        let mut account = self.accounts.get(client_id).unwrap_or_default();
+        if account.locked {
+            return Err(EngineError::UndoRefused {
+                tx_id: tx.tx_id,
+                reason: "the account is locked",
+            }
+            .into());
+        }
+        match applied.tx_type {
+            TransactionType::Deposit => {
+                // The deposit may since have been spent
+                if account.available < amount || account.total < amount {
+                    return Err(EngineError::UndoRefused {
+                        tx_id: tx.tx_id,
+                        reason: "it would overdraw the account",
+                    }
+                    .into());
+                }
+                account.available -= amount;
+                account.total -= amount;
+                self.deposit_totals.entry(client_id).or_default().deposited -= amount;
+                self.mirror_to_control_account(-amount);
+            }
+            TransactionType::Withdrawal => {
+                account.available += amount;
+                account.total += amount;
+                self.mirror_to_control_account(amount);
+            }
+            _ => return Ok(false),
+        }
+        self.accounts.insert(client_id, account);
+        self.transactions.remove(&tx.tx_id);
+        Ok(true)
+    }
+
+    /// Processes raw CSV lines of transactions, without a header row, in order. A standard
+    /// `type,client,tx,amount` header is added in front of them, otherwise this behaves the same as
+    /// `process_reader`.
+    pub fn process_lines(&mut self, lines: &[&str]) -> anyhow::Result<usize> {
+        let mut input = String::from(INPUT_HEADER);
+        for line in lines {
+            input.push('\n');
+            input.push_str(line);
+        }
+        self.process_reader(input.as_bytes())
+    }
+
+    /// Creates a handle which processes the given CSV source a record at a time and can be paused
+    /// and resumed, for callers that need to apply backpressure. The header row is read and
+    /// checked straight away.
+    pub fn processor<R: Read>(&mut self, reader: R) -> anyhow::Result<ReaderProcessor<'_, R, S>> {
+        let mut rdr = csv::Reader::from_reader(reader);
+        let headers = rdr.headers().context("Failed to read header")?;
+        let has_required_columns = REQUIRED_COLUMNS
+            .iter()
+            .all(|column| headers.iter().any(|header| header == *column));
+        if !has_required_columns {
+            return Err(EngineError::MissingHeader.into());
+        }
+        let headers = headers.clone();
+        let type_column = headers.iter().position(|header| header == "type");
+        let tx_column = headers.iter().position(|header| header == "tx");
+        Ok(ReaderProcessor {
+            engine: self,
+            records: rdr.into_records(),
+            headers,
+            type_column,
+            tx_column,
+            rows: 0,
+            last_client: None,
+            paused: false,
+            finished: false,
+        })
+    }
+
+    /// Processes the given CSV source like `process_reader`, also returning how long processing
+    /// took so throughput can be measured without external tooling.
+    pub fn process_reader_timed<R: Read>(
+        &mut self,
+        reader: R,
+    ) -> anyhow::Result<(usize, Duration)> {
+        let start = Instant::now();
+        let rows = self.process_reader(reader)?;
+        Ok((rows, start.elapsed()))
+    }
+
+    /// Processes the given CSV source like `process_reader` but sleeps for `delay` after each
+    /// record, for demonstrations and rate-limited downstreams.
+    #[cfg(feature = "demo")]
+    pub fn process_reader_throttled<R: Read>(
+        &mut self,
+        reader: R,
+        delay: Duration,
+    ) -> anyhow::Result<usize> {
+        let mut processor = self.processor(reader)?;
+        while processor.step()? {
+            std::thread::sleep(delay);
+        }
+        Ok(processor.rows())
+    }
+
+    /// Processes every transaction in the given async CSV source like `process_reader`, awaiting
+    /// each line of input only once the previous transaction has been applied so a slow engine
+    /// holds back the source. Records must each be on a single line. Returns the number of
+    /// records read.
+    #[cfg(feature = "async")]
+    pub async fn process_async_reader<R>(&mut self, reader: R) -> anyhow::Result<usize>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        use tokio::io::AsyncBufReadExt;
+
+        let mut reader = tokio::io::BufReader::new(reader);
+        let mut header = String::new();
+        reader
+            .read_line(&mut header)
+            .await
+            .context("Failed to read header")?;
+        if !header.ends_with('\n') {
+            header.push('\n');
+        }
+        // Check the header straight away rather than on the first record
+        self.processor(header.as_bytes())?;
+        let mut rows = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader
+                .read_line(&mut line)
+                .await
+                .context("Failed to read record")?;
+            if read == 0 {
+                break;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            // Each record is read through its own processor, carrying on the row count so
+            // failures are reported against the right row
+            let record = format!("{}{}", header, line);
+            let mut processor = self.processor(record.as_bytes())?;
+            processor.rows = rows;
+            processor.step()?;
+            rows = processor.rows();
+        }
+        Ok(rows)
+    }
+
+    /// Processes transactions from a paginated source such as an API, calling `fetch` with page
+    /// numbers counting up from zero until it returns `None` and processing each page in order as
+    /// it arrives. Failures are handled by the error policy with rows counted across all pages.
+    /// Returns the number of transactions fetched.
+    pub fn process_paginated(
+        &mut self,
+        mut fetch: impl FnMut(usize) -> Option<Vec<Transaction>>,
+    ) -> anyhow::Result<usize> {
+        let mut rows = 0;
+        let mut page = 0;
+        while let Some(txs) = fetch(page) {
+            for tx in txs {
+                rows += 1;
+                if let Err(err) = self.process_transaction(tx) {
+                    self.handle_error(rows, err)?;
+                }
+            }
+            page += 1;
+        }
+        Ok(rows)
+    }
+
+    /// Processes transactions tagged with the offset they were read at in their source, returning
+    /// the offset of the last transaction that was successfully processed so a consumer knows how
+    /// far it can commit. Failures are handled by the error policy, under `ErrorPolicy::Abort`
+    /// processing stops at the first failure and returns its error, naming the failed offset, and
+    /// under `ErrorPolicy::Collect` the error's row is the 1-based position of the transaction in
+    /// `iter`. Returns `None` if no transaction was successfully processed.
+    pub fn process_with_offsets<I>(&mut self, iter: I) -> anyhow::Result<Option<u64>>
+    where
+        I: IntoIterator<Item = (u64, Transaction)>,
+    {
+        let mut last_offset = None;
+        for (idx, (offset, tx)) in iter.into_iter().enumerate() {
+            match self.process_transaction(tx) {
+                Ok(()) => last_offset = Some(offset),
+                Err(err) => self
+                    .handle_error(idx + 1, err)
+                    .with_context(|| format!("Failed at offset {}", offset))?,
+            }
+        }
+        Ok(last_offset)
+    }
+
+    /// Processes every transaction in the given CSV source like `process_reader` while building a
+    /// report of every record that failed, in a single pass. Failures are still handled by the
+    /// error policy but are reported even when the policy drops them. Returns the state of every
+    /// account sorted by Id along with the report.
+    pub fn process_and_validate_reader<R: Read>(
+        &mut self,
+        reader: R,
+    ) -> (Vec<AccountWithId>, ValidationReport) {
+        self.validation_issues = Some(Vec::new());
+        let (rows, complete, header_issue) = match self.processor(reader) {
+            Ok(mut processor) => {
+                let complete = processor.run().is_ok();
+                (processor.rows(), complete, None)
+            }
+            Err(err) => (0, false, Some((0, EngineError::from_anyhow(&err)))),
+        };
+        let mut issues = self.validation_issues.take().unwrap_or_default();
+        issues.extend(header_issue);
+        let mut accounts: Vec<AccountWithId> = self.retrieve_accounts().collect();
+        accounts.sort_by_key(|acct| acct.id);
+        let report = ValidationReport {
+            rows,
+            issues,
+            complete,
+        };
+        (accounts, report)
+    }
+
+    /// Applies the error policy to a failure on the given row, returning the error if processing
+    /// should stop.
+    fn handle_error(&mut self, row: usize, err: Error) -> anyhow::Result<()> {
+        if let Some(issues) = self.validation_issues.as_mut() {
+            issues.push((row, EngineError::from_anyhow(&err)));
+        }
+        match self.config.error_policy {
+            ErrorPolicy::Abort => return Err(err),
+            ErrorPolicy::Skip => {}
+            ErrorPolicy::Collect => self.errors.push((row, EngineError::from_anyhow(&err))),
+        }
+        self.skipped_rows += 1;
+        Ok(())
+    }
+
+    /// The number of faults injected so far by `with_fault_injection`.
+    pub fn injected_faults(&self) -> usize {
+        self.fault_injector
+            .as_ref()
+            .map_or(0, |injector| injector.injected)
+    }
+
+    /// The number of records dropped so far under `ErrorPolicy::Skip` or `ErrorPolicy::Collect`.
+    pub fn skipped_rows(&self) -> usize {
+        self.skipped_rows
+    }
+
+    /// The number of transactions given to the engine so far, whatever became of them.
+    pub fn transactions_seen(&self) -> u64 {
+        self.sequence
+    }
+
+    /// The errors kept under `ErrorPolicy::Collect`, each with the 1-based input row (not
+    /// counting the header) it occurred on.
+    pub fn errors(&self) -> &[(usize, EngineError)] {
+        &self.errors
+    }
+
+    /// Serializes the errors kept under `ErrorPolicy::Collect` as a JSON array of objects with
+    /// `row`, `kind` and `message` fields.
+    pub fn errors_as_json(&self) -> String {
+        let records: Vec<ErrorRecord> = self
+            .errors
+            .iter()
+            .map(|(row, err)| ErrorRecord {
+                row: *row,
+                kind: err.kind(),
+                message: err.to_string(),
+            })
+            .collect();
+        // Serializing plain strings and numbers can't fail
+        serde_json::to_string(&records).expect("Failed to serialize errors")
+    }
+
+    /// Processes every transaction in the given CSV source like `process_reader` and returns only
+    /// the accounts whose state differs from before processing, sorted by the account Id.
+    pub fn process_reader_changed<R: Read>(
+        &mut self,
+        reader: R,
+    ) -> anyhow::Result<Vec<AccountWithId>> {
+        let baseline: HashMap<u16, Account> = self.accounts.iter().collect();
+        self.process_reader(reader)?;
+        let mut changed: Vec<AccountWithId> = self
+            .retrieve_accounts()
+            .filter(|acct| baseline.get(&acct.id) != Some(&acct.account))
+            .collect();
+        changed.sort_by_key(|acct| acct.id);
+        Ok(changed)
+    }
+
+    /// Processes the given transaction creating & updating the client's account as necessary.
+    pub fn process_transaction(&mut self, tx: Transaction) -> anyhow::Result<()> {
+        self.process(Cow::Owned(tx)).map(|_| ())
+    }
+
+    /// Processes the given transaction like `process_transaction` without taking ownership of it.
+    /// Deposits and withdrawals are cloned as they're stored in case of a later dispute.
+    pub fn process_transaction_ref(&mut self, tx: &Transaction) -> anyhow::Result<()> {
+        self.process(Cow::Borrowed(tx)).map(|_| ())
+    }
+
+    /// Processes the given transaction like `process_transaction`, first appending it to the
+    /// write-ahead log `wal` as a line of JSON tagged with its sequence number. The log is flushed
+    /// before the transaction is applied so a crash never loses an applied transaction, and it can
+    /// be replayed on top of a snapshot with `recover`.
+    pub fn process_transaction_logged<W: Write>(
+        &mut self,
+        tx: Transaction,
+        wal: &mut W,
+    ) -> anyhow::Result<()> {
+        let entry = WalEntry {
+            seq: self.sequence + 1,
+            tx: Cow::Borrowed(&tx),
+        };
+        serde_json::to_writer(&mut *wal, &entry).context("Failed to write WAL entry")?;
+        writeln!(wal).context("Failed to write WAL entry")?;
+        wal.flush().context("Failed to flush WAL")?;
+        self.process_transaction(tx)
+    }
+
+    /// Processes a batch of transactions in order, carrying on past any that fail, and returns
+    /// what happened to each transaction in input order along with the final state of every
+    /// account sorted by Id.
+    pub fn process_full(
+        &mut self,
+        txs: Vec<Transaction>,
+    ) -> (Vec<ProcessOutcome>, Vec<AccountWithId>) {
+        let outcomes = txs
+            .into_iter()
+            .map(|tx| match self.process(Cow::Owned(tx)) {
+                Ok(outcome) => outcome,
+                Err(err) => ProcessOutcome::Failed(EngineError::from_anyhow(&err)),
+            })
+            .collect();
+        let mut accounts: Vec<AccountWithId> = self.retrieve_accounts().collect();
+        accounts.sort_by_key(|acct| acct.id);
+        (outcomes, accounts)
+    }
+
+    fn process(&mut self, mut tx: Cow<Transaction>) -> anyhow::Result<ProcessOutcome> {
+        self.sequence += 1;
+        if let Some(client_id) = self.config.client_id_map.get(&tx.client_id) {
+            tx.to_mut().client_id = *client_id;
+        }
+        if self.config.retain_history {
+            let history = self.history.entry(tx.client_id).or_default();
+            history.push(tx.as_ref().clone());
+        }
+        trace!(
+            self,
+            "Processing {} {} for client {}",
+            tx.tx_type.as_str(),
+            tx.tx_id,
+            tx.client_id
+        );
+        if !self.config.data_mode.accepts(&tx) {
+            trace!(
+                self,
+                "Ignored: not accepted in {:?} mode",
+                self.config.data_mode
+            );
+            return Ok(ProcessOutcome::FilteredByDataMode);
+        }
+
+        if let Some(allowlist) = &self.config.client_allowlist {
+            if !allowlist.contains(&tx.client_id) {
+                return Err(EngineError::ClientNotAllowed(tx.client_id).into());
+            }
+        }
+        if self.config.reject_zero_client_id && tx.client_id == 0 {
+            return Err(EngineError::ClientNotAllowed(0).into());
+        }
+        if self.config.control_account == Some(tx.client_id) {
+            return Err(EngineError::ClientNotAllowed(tx.client_id).into());
+        }
+        if self.config.reject_while_frozen && self.global_freeze.is_some() {
+            return Err(EngineError::GloballyFrozen.into());
+        }
+
+        // The key is only recorded once the transaction is applied, so a failure can be retried
+        let idempotency_key = match &tx.idempotency_key {
+            Some(key) if self.config.idempotency_keys => {
+                if self.idempotency_keys.contains(key) {
+                    trace!(self, "Dropped as idempotency key {} was already seen", key);
+                    return Ok(ProcessOutcome::DuplicateIdempotencyKey);
+                }
+                Some(key.clone())
+            }
+            _ => None,
+        };
+
+        // Retransmitted dispute messages seen within the dedup window are dropped
+        if self.is_retransmitted_dispute_message(&tx) {
+            trace!(self, "Dropped as a retransmit of a recent dispute message");
+            return Ok(ProcessOutcome::DuplicateDisputeMessage);
+        }
+
+        // Any amount given on a dispute, resolve or chargeback is ignored in favour of the amount
+        // of the transaction it references
+        if !tx.tx_type.carries_amount() && tx.amount.is_some() {
+            trace!(self, "Ignoring the amount given on the transaction");
+            self.ignored_amounts += 1;
+        }
+
+        if let Some(filter) = &self.duplicate_filter {
+            if tx.tx_type.carries_amount() && filter.contains(tx.tx_id) {
+                return Err(EngineError::DuplicateTransaction(tx.tx_id).into());
+            }
+        }
+
+        // If this is the first transaction for the client create an account and insert that
+        // otherwise get the existing account
+        let mut account = match self.accounts.get(tx.client_id) {
+            Some(account) => account,
+            None => {
+                trace!(self, "Creating a new account for client {}", tx.client_id);
+                self.account_order.push(tx.client_id);
+                self.accounts.insert(tx.client_id, Account::default());
+                Account::default()
+            }
+        };
+        // Changes are made to a copy of the account which is written back to the store once the
+        // transaction has been applied
+        let tx_account = &mut account;
+        trace!(
+            self,
+            "Account before: available {}, held {}, total {}, locked {}",
+            tx_account.available,
+            tx_account.held,
+            tx_account.total,
+            tx_account.locked
+        );
+
+        // If the account is locked we won't do any further processing other than unlocking it,
+        // which neither a global freeze nor a lock by chargeback allow
+        let unlockable = tx.tx_type == TransactionType::Unlock
+            && self.global_freeze.is_none()
+            && !self
+                .lock_records
+                .iter()
+                .any(|record| record.client_id == tx.client_id);
+        if tx_account.locked && !unlockable {
+            // It may be better to treat this as an error case
+            trace!(self, "Ignored: the account is locked");
+            return Ok(ProcessOutcome::AccountLocked);
+        }
+
+        let client_id = tx.client_id;
+        let tx_id = tx.tx_id;
+        let tx_type = tx.tx_type;
+        let before = *tx_account;
+
+        let is_dispute_action = matches!(
+            tx_type,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        );
+        // Only deposits and withdrawals can be disputed, whatever else may have been stored
+        let targets_non_disputable = self
+            .transactions
+            .get(&tx_id)
+            .is_some_and(|target| !target.tx_type.carries_amount());
+        if is_dispute_action && targets_non_disputable {
+            return Err(EngineError::NonDisputableType(tx_id).into());
+        }
+        if is_dispute_action && self.config.enforce_lifecycle {
+            if let Some(target) = self.transactions.get(&tx_id) {
+                let disputed = self.disputed_transactions.contains(&tx_id);
+                let in_order = target.client_id == client_id
+                    && !self.charged_back_transactions.contains(&tx_id)
+                    && match tx_type {
+                        TransactionType::Dispute => !disputed,
+                        TransactionType::Resolve => {
+                            disputed || self.resolved_transactions.contains(&tx_id)
                         }
-                        // Now that we have processed the chargeback we can mark the
-                        // transaction as no longer disputed
-                        self.disputed_transactions.remove(&tx.tx_id);
-                        // Processing a chargeback results in locking of the client's
-                        // account
-                        tx_account.locked = true
+                        _ => disputed,
+                    };
+                if !in_order {
+                    return Err(EngineError::LifecycleViolation {
+                        tx_id,
+                        action: tx_type.as_str(),
                     }
+                    .into());
                 }
             }
         }
-        anyhow::Result::Ok(())
+
+        // Cap how many dispute actions any one transaction can go through
+        if let Some(max_actions) = self.config.max_dispute_actions {
+            let actions = self
+                .dispute_actions
+                .get(&tx_id)
+                .copied()
+                .unwrap_or_default();
+            if is_dispute_action && actions >= max_actions {
+                return Err(EngineError::DisputeActionLimit(tx_id).into());
+            }
+        }
+
+        // Take appropriate action based on the transaction type
+        let outcome = match tx.tx_type {
+            TransactionType::Deposit => {
+                let tx_amount = tx.amount().context("Failed to get deposit amount")?;
+                if tx_amount.is_zero() && !self.config.allow_zero_deposits {
+                    return Err(EngineError::ZeroDeposit.into());
+                }
+                if let Some(threshold) = self.config.min_meaningful_amount {
+                    if tx_amount < threshold {
+                        return Err(EngineError::DustDeposit {
+                            amount: tx_amount,
+                            threshold,
+                        }
+                        .into());
+                    }
+                }
+                if let Some(minimum) = self.config.min_deposit {
+                    if tx_amount < minimum {
+                        return Err(EngineError::BelowMinimumDeposit {
+                            amount: tx_amount,
+                            minimum,
+                        }
+                        .into());
+                    }
+                }
+                if let Some(maximum) = self.config.max_total_balance {
+                    if tx_account.total + tx_amount > maximum {
+                        return Err(EngineError::AboveMaximumBalance {
+                            amount: tx_amount,
+                            maximum,
+                        }
+                        .into());
+                    }
+                }
+                tx_account.total += tx_amount;
+                tx_account.available += tx_amount;
+                trace!(self, "Deposited {} into available", tx_amount);
+                self.mirror_to_control_account(tx_amount);
+                self.deposit_totals.entry(client_id).or_default().deposited += tx_amount;
+                self.funded_clients.insert(client_id);
+                // Store this transaction in case of later dispute. This must happen before the
+                // deposit is reported as processed so a dispute straight after it can find it.
+                self.transactions.insert(tx.tx_id, tx.into_owned());
+                ProcessOutcome::Applied
+            }
+            TransactionType::Withdrawal => {
+                let tx_amount = tx.amount().context("Failed to get withdrawal amount")?;
+                let counts = self.withdrawal_counts.entry(client_id).or_default();
+                counts.attempted += 1;
+                // Only process this withdrawal if the account has sufficient available funds,
+                // including any overdraft allowed
+                if tx_account.available + self.config.overdraft_limit >= tx_amount {
+                    tx_account.total -= tx_amount;
+                    tx_account.available -= tx_amount;
+                    trace!(self, "Withdrew {} from available", tx_amount);
+                    counts.succeeded += 1;
+                    self.mirror_to_control_account(-tx_amount);
+                    self.funded_clients.insert(client_id);
+                    // Store this transaction in case of later dispute
+                    self.transactions.insert(tx.tx_id, tx.into_owned());
+                    ProcessOutcome::Applied
+                } else {
+                    trace!(
+                        self,
+                        "Rejected: insufficient funds to withdraw {} with {} available",
+                        tx_amount,
+                        tx_account.available
+                    );
+                    ProcessOutcome::InsufficientFunds
+                }
+            }
+            TransactionType::Dispute => {
+                // Only dispute this transaction if the transaction Id refers to a valid transaction
+                if let Some(disputed_tx) = self.transactions.get(&tx.tx_id) {
+                    let disputed_tx_amount = disputed_tx
+                        .amount()
+                        .context("Failed to get disputed transaction amount")?;
+                    if let Some(max_open) = self.config.max_total_open_disputes {
+                        if !self.disputed_transactions.contains(&tx.tx_id)
+                            && self.disputed_transactions.len() >= max_open
+                        {
+                            return Err(EngineError::OpenDisputeLimit(tx.tx_id).into());
+                        }
+                    }
+                    match disputed_tx.tx_type {
+                        TransactionType::Deposit => {
+                            // If the deposit has since been spent, holding it would leave held
+                            // above total and available below zero
+                            if tx_account.available < disputed_tx_amount {
+                                if self.config.reject_overdrawn_disputes {
+                                    return Err(
+                                        EngineError::DisputeExceedsAvailable(tx.tx_id).into()
+                                    );
+                                }
+                                self.overdrawn_disputes.insert(client_id);
+                            }
+                            tx_account.available -= disputed_tx_amount;
+                            tx_account.held += disputed_tx_amount;
+                            trace!(
+                                self,
+                                "Moved the disputed deposit's {} from available to held",
+                                disputed_tx_amount
+                            );
+                        }
+                        TransactionType::Withdrawal => {
+                            tx_account.total += disputed_tx_amount;
+                            tx_account.held += disputed_tx_amount;
+                            trace!(
+                                self,
+                                "Held the disputed withdrawal's {} and added it to total",
+                                disputed_tx_amount
+                            );
+                        }
+                        _ => return Err(EngineError::NonDisputableType(tx.tx_id).into()),
+                    }
+                    self.disputed_transactions.insert(disputed_tx.tx_id);
+                    self.resolved_transactions.remove(&tx.tx_id);
+                    self.ever_disputed.insert(client_id);
+                    ProcessOutcome::Applied
+                } else {
+                    trace!(self, "Ignored: transaction {} is not known", tx.tx_id);
+                    ProcessOutcome::UnknownTransaction
+                }
+            }
+            TransactionType::Resolve => {
+                // The transaction must both refer to a valid existing transaction and that
+                // transaction must be currently disputed in order for us to process a resolve
+                if let Some(disputed_tx) = self.transactions.get(&tx.tx_id) {
+                    if self.disputed_transactions.contains(&tx.tx_id) {
+                        let mut disputed_tx_amount = disputed_tx
+                            .amount()
+                            .context("Failed to get disputed transaction amount")?;
+                        // Releasing more than is held would leave available above total
+                        if tx_account.held < disputed_tx_amount {
+                            match self.config.resolve_shortfall_policy {
+                                ResolveShortfallPolicy::Allow => {}
+                                ResolveShortfallPolicy::Reject => {
+                                    return Err(EngineError::ResolveExceedsHeld(tx.tx_id).into());
+                                }
+                                ResolveShortfallPolicy::Clamp => {
+                                    disputed_tx_amount = tx_account.held.max(Decimal::ZERO);
+                                    trace!(self, "Only releasing the {} held", disputed_tx_amount);
+                                }
+                            }
+                        }
+                        match disputed_tx.tx_type {
+                            TransactionType::Deposit => {
+                                tx_account.held -= disputed_tx_amount;
+                                tx_account.available += disputed_tx_amount;
+                                trace!(
+                                    self,
+                                    "Released the resolved deposit's {} from held to available",
+                                    disputed_tx_amount
+                                );
+                            }
+                            TransactionType::Withdrawal => {
+                                tx_account.total -= disputed_tx_amount;
+                                tx_account.held -= disputed_tx_amount;
+                                trace!(
+                                    self,
+                                    "Removed the resolved withdrawal's {} from held and total",
+                                    disputed_tx_amount
+                                );
+                            }
+                            _ => return Err(EngineError::NonDisputableType(tx.tx_id).into()),
+                        }
+                        // Now that we have processed the resolve we can mark the transaction as no
+                        // longer disputed
+                        self.disputed_transactions.remove(&tx.tx_id);
+                        self.resolved_transactions.insert(tx.tx_id);
+                        ProcessOutcome::Applied
+                    } else if self.resolved_transactions.contains(&tx.tx_id) {
+                        // A retransmitted resolve has nothing left to do, even under strict
+                        // settlement
+                        trace!(
+                            self,
+                            "Ignored: transaction {} is already resolved",
+                            tx.tx_id
+                        );
+                        self.duplicate_resolves += 1;
+                        ProcessOutcome::DuplicateResolve
+                    } else {
+                        trace!(self, "Ignored: transaction {} is not disputed", tx.tx_id);
+                        self.resolves_without_dispute += 1;
+                        if self.config.strict_settlement {
+                            return Err(EngineError::ResolveWithoutDispute(tx.tx_id).into());
+                        }
+                        ProcessOutcome::ResolveWithoutDispute
+                    }
+                } else {
+                    trace!(self, "Ignored: transaction {} is not known", tx.tx_id);
+                    ProcessOutcome::UnknownTransaction
+                }
+            }
+            TransactionType::Chargeback => {
+                // The transaction must both refer to a valid existing transaction and that
+                // transaction must be currently disputed in order for us to process a chargeback
+                if let Some(disputed_tx) = self.transactions.get(&tx.tx_id) {
+                    if self.disputed_transactions.contains(&tx.tx_id) {
+                        let disputed_tx_amount = disputed_tx
+                            .amount()
+                            .context("Failed to get disputed transaction amount")?;
+                        match disputed_tx.tx_type {
+                            TransactionType::Deposit => {
+                                // A deposit that has since been spent leaves less in total than
+                                // is charged back
+                                if tx_account.total < disputed_tx_amount {
+                                    match self.config.negative_total_policy {
+                                        NegativeTotalPolicy::Allow => {}
+                                        NegativeTotalPolicy::Flag => {
+                                            self.negative_total_chargebacks.insert(client_id);
+                                        }
+                                        NegativeTotalPolicy::Reject => {
+                                            return Err(EngineError::ChargebackExceedsTotal(
+                                                tx.tx_id,
+                                            )
+                                            .into());
+                                        }
+                                    }
+                                }
+                                tx_account.held -= disputed_tx_amount;
+                                tx_account.total -= disputed_tx_amount;
+                                self.deposit_totals
+                                    .entry(client_id)
+                                    .or_default()
+                                    .charged_back += disputed_tx_amount;
+                                trace!(
+                                    self,
+                                    "Charged back the deposit's {} from held and total",
+                                    disputed_tx_amount
+                                );
+                            }
+                            TransactionType::Withdrawal => {
+                                tx_account.held -= disputed_tx_amount;
+                                tx_account.available += disputed_tx_amount;
+                                trace!(
+                                    self,
+                                    "Charged back the withdrawal's {} from held to available",
+                                    disputed_tx_amount
+                                );
+                            }
+                            _ => return Err(EngineError::NonDisputableType(tx.tx_id).into()),
+                        }
+                        // Now that we have processed the chargeback we can mark the
+                        // transaction as no longer disputed
+                        self.disputed_transactions.remove(&tx.tx_id);
+                        self.charged_back_transactions.insert(tx.tx_id);
+                        // Processing a chargeback results in locking of the client's
+                        // account, once they have had enough chargebacks
+                        let chargebacks = self.chargeback_counts.entry(client_id).or_default();
+                        *chargebacks += 1;
+                        let threshold = self.config.chargeback_lock_threshold.unwrap_or(1);
+                        if *chargebacks >= threshold && !self.config.chargebacks_never_lock {
+                            tx_account.locked = true;
+                            trace!(self, "Locked the account after {} chargebacks", chargebacks);
+                            self.lock_records.push(LockRecord {
+                                client_id,
+                                tx_id: tx.tx_id,
+                                amount: disputed_tx_amount,
+                                account: AccountWithId {
+                                    id: client_id,
+                                    account: *tx_account,
+                                },
+                            });
+                        }
+                        ProcessOutcome::Applied
+                    } else {
+                        trace!(self, "Ignored: transaction {} is not disputed", tx.tx_id);
+                        self.resolves_without_dispute += 1;
+                        if self.config.strict_settlement {
+                            return Err(EngineError::ResolveWithoutDispute(tx.tx_id).into());
+                        }
+                        ProcessOutcome::ResolveWithoutDispute
+                    }
+                } else {
+                    trace!(self, "Ignored: transaction {} is not known", tx.tx_id);
+                    ProcessOutcome::UnknownTransaction
+                }
+            }
+            TransactionType::Lock => {
+                tx_account.locked = true;
+                trace!(self, "Locked the account");
+                ProcessOutcome::Applied
+            }
+            TransactionType::Unlock => {
+                tx_account.locked = false;
+                trace!(self, "Unlocked the account");
+                ProcessOutcome::Applied
+            }
+        };
+        trace!(
+            self,
+            "Account after: available {}, held {}, total {}, locked {}",
+            tx_account.available,
+            tx_account.held,
+            tx_account.total,
+            tx_account.locked
+        );
+        self.accounts.insert(client_id, account);
+
+        // Only applied transactions are seen, so a rejected one can be retried with the same Id
+        if let Some(filter) = &mut self.duplicate_filter {
+            if tx_type.carries_amount() && outcome == ProcessOutcome::Applied {
+                filter.insert(tx_id);
+            }
+        }
+        if let Some(key) = idempotency_key {
+            if outcome == ProcessOutcome::Applied {
+                self.idempotency_keys.insert(key);
+            }
+        }
+        // Likewise only applied dispute actions count towards the cap and the dedup window
+        if is_dispute_action && outcome == ProcessOutcome::Applied {
+            if self.config.max_dispute_actions.is_some() {
+                *self.dispute_actions.entry(tx_id).or_default() += 1;
+            }
+            self.record_dispute_message(tx_type, client_id, tx_id);
+        }
+        self.after_transaction(client_id, tx_type);
+        self.send_balance_event(client_id, tx_id, tx_type, &before);
+        Ok(outcome)
+    }
+
+    /// Describes step by step what processing the given transaction would do to the engine,
+    /// including the lookups and checks made and any balance changes, without changing the
+    /// engine. The transaction is processed against a fork of the whole engine to produce this.
+    pub fn explain(&self, tx: &Transaction) -> String {
+        let mut fork = self.fork();
+        fork.trace = Some(Vec::new());
+        let res = fork.process_transaction_ref(tx);
+        let mut trace = fork.trace.take().unwrap_or_default();
+        if let Err(err) = res {
+            trace.push(format!("Failed: {:#}", err));
+        }
+        trace.join("\n")
+    }
+
+    /// Adds the net change of a deposit or withdrawal to the control account, if there is one.
+    fn mirror_to_control_account(&mut self, amount: Decimal) {
+        let control_id = match self.config.control_account {
+            Some(control_id) => control_id,
+            None => return,
+        };
+        let mut control = match self.accounts.get(control_id) {
+            Some(control) => control,
+            None => {
+                self.account_order.push(control_id);
+                Account::default()
+            }
+        };
+        control.available += amount;
+        control.total += amount;
+        self.accounts.insert(control_id, control);
+    }
+
+    /// Housekeeping and checks run on the client's account once a transaction has been applied.
+    fn after_transaction(&mut self, client_id: u16, tx_type: TransactionType) {
+        let mut tx_account = match self.accounts.get(client_id) {
+            Some(tx_account) => tx_account,
+            None => return,
+        };
+
+        // Once a dispute settles any held balance left below the dust threshold, with no open
+        // disputes left to back it, is released back to available so it doesn't linger as held
+        let settles_dispute = matches!(
+            tx_type,
+            TransactionType::Resolve | TransactionType::Chargeback
+        );
+        if let Some(epsilon) = self.config.held_dust_epsilon {
+            if settles_dispute && !tx_account.held.is_zero() && tx_account.held.abs() < epsilon {
+                let transactions = &self.transactions;
+                let has_open_disputes = self.disputed_transactions.iter().any(|tx_id| {
+                    transactions
+                        .get(tx_id)
+                        .is_some_and(|disputed_tx| disputed_tx.client_id == client_id)
+                });
+                if !has_open_disputes {
+                    tx_account.available += tx_account.held;
+                    tx_account.held = Decimal::ZERO;
+                    self.accounts.insert(client_id, tx_account);
+                }
+            }
+        }
+
+        // Flag the account if too much of its total is held, once flagged it stays flagged
+        if let Some(max_ratio) = self.config.max_held_ratio {
+            if tx_account.total > Decimal::ZERO {
+                let exceeded = tx_account
+                    .held
+                    .checked_div(tx_account.total)
+                    .is_some_and(|ratio| ratio > max_ratio);
+                if exceeded {
+                    self.held_ratio_flagged.insert(client_id);
+                }
+            }
+        }
+
+        // The same check across the whole system, which also stays flagged once raised
+        if let Some(max_ratio) = self.config.max_system_held_ratio {
+            if !self.systemic_dispute_flagged {
+                let (held, total) = self.client_accounts().fold(
+                    (Decimal::ZERO, Decimal::ZERO),
+                    |(held, total), (_, account)| (held + account.held, total + account.total),
+                );
+                if total > Decimal::ZERO {
+                    self.systemic_dispute_flagged = held
+                        .checked_div(total)
+                        .is_some_and(|ratio| ratio > max_ratio);
+                }
+            }
+        }
+    }
+
+    /// Sends a balance event for the client's account if there is an event channel and the
+    /// account no longer matches its state from before the transaction.
+    fn send_balance_event(
+        &mut self,
+        client_id: u16,
+        tx_id: TxId,
+        tx_type: TransactionType,
+        before: &Account,
+    ) {
+        let sender = match &self.event_sender {
+            Some(sender) => sender,
+            None => return,
+        };
+        let account = match self.accounts.get(client_id) {
+            Some(account) if account != *before => account,
+            _ => return,
+        };
+        let event = BalanceEvent {
+            tx_id,
+            tx_type,
+            account: AccountWithId {
+                id: client_id,
+                account,
+            },
+        };
+        // The receiver has hung up so nobody is listening any more
+        if sender.send(event).is_err() {
+            self.event_sender = None;
+        }
+    }
+
+    /// Checks whether the given dispute, resolve or chargeback is a repeat of one applied within
+    /// the dedup window.
+    fn is_retransmitted_dispute_message(&self, tx: &Transaction) -> bool {
+        self.config.dispute_dedup_window > 0
+            && self
+                .recent_dispute_messages
+                .contains(&(tx.tx_type, tx.client_id, tx.tx_id))
+    }
+
+    /// Records an applied dispute, resolve or chargeback in the dedup window, dropping the oldest
+    /// message once the window is full.
+    fn record_dispute_message(&mut self, tx_type: TransactionType, client_id: u16, tx_id: TxId) {
+        let window = self.config.dispute_dedup_window;
+        if window == 0 {
+            return;
+        }
+        self.recent_dispute_messages
+            .push_back((tx_type, client_id, tx_id));
+        if self.recent_dispute_messages.len() > window {
+            self.recent_dispute_messages.pop_front();
+        }
+    }
+
+    /// The Ids of the clients whose held to total ratio has gone above the maximum set with
+    /// `with_max_held_ratio`, sorted by Id.
+    pub fn held_ratio_flagged(&self) -> Vec<u16> {
+        let mut flagged: Vec<u16> = self.held_ratio_flagged.iter().copied().collect();
+        flagged.sort_unstable();
+        flagged
+    }
+
+    /// The fraction of the client's attempted withdrawals that were applied, counting those
+    /// rejected for insufficient funds as attempted. This is `None` for clients who haven't
+    /// attempted a withdrawal.
+    pub fn withdrawal_success_rate(&self, client_id: u16) -> Option<f64> {
+        self.withdrawal_counts
+            .get(&client_id)
+            .filter(|counts| counts.attempted > 0)
+            .map(|counts| counts.succeeded as f64 / counts.attempted as f64)
+    }
+
+    /// The amount deposited by every client who has made a deposit, sorted by Id, either gross or
+    /// net of the deposits that were charged back for a summarized ledger.
+    pub fn deposit_ledger(&self, reporting: DepositReporting) -> Vec<(u16, Decimal)> {
+        let mut ledger: Vec<(u16, Decimal)> = self
+            .deposit_totals
+            .iter()
+            .map(|(id, totals)| match reporting {
+                DepositReporting::Gross => (*id, totals.deposited),
+                DepositReporting::Net => (*id, totals.deposited - totals.charged_back),
+            })
+            .collect();
+        ledger.sort_unstable_by_key(|(id, _)| *id);
+        ledger
+    }
+
+    /// Every retained transaction of the given type, sorted by transaction Id, for auditing. Only
+    /// deposits and withdrawals are retained so that they can be disputed, so this is always empty
+    /// for any other type.
+    pub fn transactions_of_type(&self, ty: TransactionType) -> Vec<&Transaction> {
+        let mut transactions: Vec<&Transaction> = self
+            .transactions
+            .values()
+            .filter(|tx| tx.tx_type == ty)
+            .collect();
+        transactions.sort_unstable_by_key(|tx| tx.tx_id);
+        transactions
+    }
+
+    /// The number of chargebacks processed so far for the client.
+    pub fn chargeback_count(&self, client_id: u16) -> usize {
+        self.chargeback_counts
+            .get(&client_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The Ids of the clients whose total was taken below zero by a chargeback, sorted by Id. These
+    /// are only recorded under `NegativeTotalPolicy::Flag`.
+    pub fn negative_total_chargebacks(&self) -> Vec<u16> {
+        let mut clients: Vec<u16> = self.negative_total_chargebacks.iter().copied().collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// The Ids of the clients who have had a deposit disputed for more than their available
+    /// balance, sorted by Id. See `with_reject_overdrawn_disputes`.
+    pub fn overdrawn_disputes(&self) -> Vec<u16> {
+        let mut clients: Vec<u16> = self.overdrawn_disputes.iter().copied().collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// The Ids of the clients who have ever had a transaction disputed, sorted by Id. Clients stay
+    /// in this list once their disputes are resolved, for risk scoring.
+    pub fn accounts_ever_disputed(&self) -> Vec<u16> {
+        let mut clients: Vec<u16> = self.ever_disputed.iter().copied().collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Whether held across all accounts has gone above the fraction of their total set with
+    /// `with_max_system_held_ratio`.
+    pub fn systemic_dispute_flagged(&self) -> bool {
+        self.systemic_dispute_flagged
+    }
+
+    /// The number of disputes, resolves, chargebacks, locks and unlocks processed so far which
+    /// were given an amount. The amount on these is always ignored so a nonzero count may point to a
+    /// misbehaving upstream.
+    pub fn ignored_amounts(&self) -> usize {
+        self.ignored_amounts
+    }
+
+    /// The number of resolves and chargebacks processed so far that referred to a transaction
+    /// which wasn't disputed, such as one arriving before its dispute.
+    pub fn resolves_without_dispute(&self) -> usize {
+        self.resolves_without_dispute
+    }
+
+    /// The number of resolves processed so far that repeated one already applied to the same
+    /// dispute. These are dropped without effect and aren't counted by
+    /// `resolves_without_dispute`.
+    pub fn duplicate_resolves(&self) -> usize {
+        self.duplicate_resolves
+    }
+
+    /// Creates an independent copy of the engine, including its configuration, which can be
+    /// processed into without affecting this engine.
+    pub fn fork(&self) -> Self {
+        let mut fork = self.clone();
+        fork.event_sender = None;
+        fork
+    }
+
+    /// Applies hypothetical transactions to a fork of the engine and returns the resulting state
+    /// of every account sorted by Id, leaving this engine untouched.
+    pub fn what_if(&self, txs: &[Transaction]) -> anyhow::Result<Vec<AccountWithId>> {
+        let mut fork = self.fork();
+        for tx in txs {
+            fork.process_transaction_ref(tx)?;
+        }
+        let mut accounts: Vec<AccountWithId> = fork.retrieve_accounts().collect();
+        accounts.sort_by_key(|acct| acct.id);
+        Ok(accounts)
+    }
+
+    /// Applies only the transactions in the batch for the given client to a fork of the engine
+    /// and returns the resulting state of their account, leaving this engine untouched.
+    /// Transactions that fail have no effect, the same as when applied for real. A client left
+    /// without an account is returned as an empty account.
+    pub fn simulate_batch_for_client(&self, client_id: u16, txs: &[Transaction]) -> AccountWithId {
+        let mut fork = self.fork();
+        let client_map = &self.config.client_id_map;
+        for tx in txs {
+            if *client_map.get(&tx.client_id).unwrap_or(&tx.client_id) == client_id {
+                let _ = fork.process_transaction_ref(tx);
+            }
+        }
+        fork.account(client_id).unwrap_or(AccountWithId {
+            id: client_id,
+            account: Account::default(),
+        })
+    }
+
+    /// Processes a batch of transactions in the order given by `key` rather than the order they
+    /// are passed in, for feeds that should be applied by some other field such as the
+    /// transaction Id. Transactions with equal keys keep their original order. If any transaction
+    /// fails the error is returned and the transactions before it remain applied.
+    pub fn process_sorted_by<K: Ord>(
+        &mut self,
+        mut txs: Vec<Transaction>,
+        key: impl Fn(&Transaction) -> K,
+    ) -> anyhow::Result<()> {
+        txs.sort_by_key(key);
+        for tx in txs {
+            self.process_transaction(tx)?;
+        }
+        Ok(())
+    }
+
+    /// Processes a batch of transactions in order and returns the net change to every client
+    /// touched by the batch, including clients whose balances ended up unchanged. If any
+    /// transaction fails the error is returned and the transactions before it remain applied.
+    pub fn process_and_diff(
+        &mut self,
+        txs: Vec<Transaction>,
+    ) -> anyhow::Result<HashMap<u16, AccountDiff>> {
+        // The state of each touched account as it was before the batch began
+        let mut before: HashMap<u16, Account> = HashMap::new();
+        for tx in txs {
+            let accounts = &self.accounts;
+            before
+                .entry(tx.client_id)
+                .or_insert_with(|| accounts.get(tx.client_id).unwrap_or_default());
+            self.process_transaction(tx)?;
+        }
+        Ok(before
+            .into_iter()
+            .map(|(id, before)| {
+                let after = self.accounts.get(id).unwrap_or_default();
+                (id, AccountDiff::between(id, &before, &after))
+            })
+            .collect())
+    }
+
+    /// Retrieve an iterator of all the accounts including their Ids. This function retrieves the
+    /// state of all accounts as of a particular point in time. The account information is given
+    /// in the form of immutable copies as at the time the iterator is iterated.
+    pub fn retrieve_accounts(&self) -> impl Iterator<Item = AccountWithId> + '_ {
+        self.accounts
+            .iter()
+            .map(|(id, account)| AccountWithId { id, account })
+    }
+
+    /// Locks every account for incident response and marks the engine as globally frozen until
+    /// `unfreeze_all`, and `unlock` transactions are ignored while frozen. Freezing again while
+    /// frozen locks any accounts created since. See `with_reject_while_frozen` to also reject
+    /// transactions for new clients.
+    pub fn freeze_all(&mut self) {
+        let unlocked: Vec<(u16, Account)> = self
+            .client_accounts()
+            .filter(|(_, account)| !account.locked)
+            .collect();
+        let frozen = self.global_freeze.get_or_insert_with(HashSet::new);
+        for (id, mut account) in unlocked {
+            account.locked = true;
+            self.accounts.insert(id, account);
+            frozen.insert(id);
+        }
+    }
+
+    /// Lifts a global freeze, unlocking the accounts `freeze_all` locked. Accounts that were
+    /// already locked when the freeze was made stay locked.
+    pub fn unfreeze_all(&mut self) {
+        for id in self.global_freeze.take().unwrap_or_default() {
+            if let Some(mut account) = self.accounts.get(id) {
+                account.locked = false;
+                self.accounts.insert(id, account);
+            }
+        }
+    }
+
+    /// Whether the engine is globally frozen by `freeze_all`.
+    pub fn is_frozen(&self) -> bool {
+        self.global_freeze.is_some()
+    }
+
+    /// Applies interest at `rate` to the held balance of every account, crediting the interest to
+    /// held (and so to total) while leaving available untouched. Settling a dispute only releases
+    /// the disputed transaction's amount, so accrued interest remains held afterwards.
+    pub fn accrue_held_interest(&mut self, rate: Decimal) {
+        let held: Vec<(u16, Account)> = self
+            .accounts
+            .iter()
+            .filter(|(_, account)| !account.held.is_zero())
+            .collect();
+        for (id, mut account) in held {
+            let interest = account.held * rate;
+            account.held += interest;
+            account.total += interest;
+            self.accounts.insert(id, account);
+        }
+    }
+
+    /// Retrieve a copy of a single account's current state.
+    pub fn account(&self, client_id: u16) -> Option<AccountWithId> {
+        self.accounts.get(client_id).map(|account| AccountWithId {
+            id: client_id,
+            account,
+        })
+    }
+
+    /// The most that could currently be withdrawn from the client's account, which is its
+    /// available balance plus any overdraft allowed. This is zero for locked accounts and `None`
+    /// for clients with no account.
+    pub fn withdrawable(&self, client_id: u16) -> Option<Decimal> {
+        self.accounts.get(client_id).map(|account| {
+            if account.locked {
+                Decimal::ZERO
+            } else {
+                (account.available + self.config.overdraft_limit).max(Decimal::ZERO)
+            }
+        })
+    }
+
+    /// The client's own spendable funds, which is its available balance without any overdraft.
+    /// Disputed funds are held so never counted. This is zero for locked accounts or accounts
+    /// overdrawn by disputes, and `None` for clients with no account. Under a chargeback lock
+    /// threshold an account stays spendable until the chargeback that reaches it.
+    pub fn effective_available(&self, client_id: u16) -> Option<Decimal> {
+        self.accounts.get(client_id).map(|account| {
+            if account.locked {
+                Decimal::ZERO
+            } else {
+                account.available.max(Decimal::ZERO)
+            }
+        })
+    }
+
+    /// The state the client's account was in straight after the transaction with the given Id was
+    /// first applied, found by replaying the client's history up to and including it with the
+    /// engine's configuration. This needs `with_history` and is `None` if the client has no
+    /// transaction with the Id in their history. The history already holds remapped client Ids
+    /// so the client Id map isn't applied again, and settings spanning every client, such as the
+    /// cap on open disputes, the system held ratio and the control account, aren't applied to the
+    /// replay of a single client.
+    pub fn balance_at_tx(&self, client_id: u16, tx_id: TxId) -> Option<AccountWithId> {
+        let history = self.history.get(&client_id)?;
+        let end = history.iter().position(|tx| tx.tx_id == tx_id)?;
+        let mut replay: TransactionEngine = TransactionEngine {
+            config: self.config.clone(),
+            ..Default::default()
+        };
+        replay.config.retain_history = false;
+        replay.config.client_id_map.clear();
+        replay.config.max_total_open_disputes = None;
+        replay.config.max_system_held_ratio = None;
+        replay.config.control_account = None;
+        for tx in &history[..=end] {
+            // A transaction that failed originally had no effect and fails again here
+            let _ = replay.process_transaction_ref(tx);
+        }
+        replay.account(client_id)
+    }
+
+    /// Retrieve all the accounts in the order they were first seen in the input.
+    pub fn retrieve_accounts_insertion_order(&self) -> Vec<AccountWithId> {
+        self.account_order
+            .iter()
+            .map(|id| AccountWithId {
+                id: *id,
+                account: self.accounts.get(*id).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Formats an account's balance columns according to the engine's output options.
+    fn formatted_balances(&self, acct: &AccountWithId) -> (String, String, String) {
+        if self.config.zeroed_locked_display && acct.account.locked {
+            let zeroed = AccountWithId {
+                id: acct.id,
+                account: Account {
+                    locked: true,
+                    ..Account::default()
+                },
+            };
+            return zeroed.formatted_balances(&self.config.output_scales);
+        }
+        let (available, held, total) = acct.formatted_balances(&self.config.output_scales);
+        if self.config.reconciled_total_display {
+            // The displayed values always parse as they were just formatted from decimals
+            let parse = |value: &str| Decimal::from_str(value).unwrap_or_default();
+            let total = format!(
+                "{:.*}",
+                self.config.output_scales.total as usize,
+                parse(&available) + parse(&held)
+            );
+            return (available, held, total);
+        }
+        (available, held, total)
+    }
+
+    /// Writes all the accounts in CSV format including the header row.
+    pub fn write_accounts<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        self.write_selected_accounts(writer, self.retrieve_accounts())
+    }
+
+    /// Writes all the accounts like `write_accounts`, writing any display mismatch warnings to
+    /// `warnings`.
+    pub fn write_accounts_with_warnings<W: Write, E: Write>(
+        &self,
+        writer: W,
+        warnings: E,
+    ) -> anyhow::Result<()> {
+        self.write_selected_accounts_with_warnings(writer, self.retrieve_accounts(), warnings)
+    }
+
+    /// Writes the given accounts in CSV format including the header row, formatted according to
+    /// this engine's output options. Display mismatch warnings are dropped, see
+    /// `write_selected_accounts_with_warnings` to keep them.
+    pub fn write_selected_accounts<W, I>(&self, writer: W, accounts: I) -> anyhow::Result<()>
+    where
+        W: Write,
+        I: IntoIterator<Item = AccountWithId>,
+    {
+        self.write_selected_accounts_with_warnings(writer, accounts, io::sink())
+    }
+
+    /// Writes the given accounts like `write_selected_accounts`, writing any display mismatch
+    /// warnings to `warnings`.
+    pub fn write_selected_accounts_with_warnings<W, I, E>(
+        &self,
+        writer: W,
+        accounts: I,
+        mut warnings: E,
+    ) -> anyhow::Result<()>
+    where
+        W: Write,
+        I: IntoIterator<Item = AccountWithId>,
+        E: Write,
+    {
+        let mut writer = self.buffered(writer);
+        self.write_header(&mut writer, &self.accounts_header())?;
+        for account in accounts {
+            self.write_row(&mut writer, &account, &mut warnings)?;
+        }
+        writer.flush().context("Failed to flush accounts")
+    }
+
+    /// Writes the header row of CSV output, preceded by a byte order mark and a schema version
+    /// comment if they are configured.
+    fn write_header<W: Write>(&self, mut writer: W, header: &str) -> anyhow::Result<()> {
+        if self.config.output_bom {
+            writer
+                .write_all(UTF8_BOM.as_bytes())
+                .context("Failed to write byte order mark")?;
+        }
+        if self.config.output_schema_version {
+            writeln!(writer, "# schema: {}", SCHEMA_VERSION)
+                .context("Failed to write schema version")?;
+        }
+        writeln!(writer, "{}", header).context("Failed to write header")
+    }
+
+    /// Wraps the writer accounts are output to in a buffer of the configured size.
+    fn buffered<W: Write>(&self, writer: W) -> BufWriter<W> {
+        match self.config.output_buffer_size {
+            Some(capacity) => BufWriter::with_capacity(capacity, writer),
+            None => BufWriter::new(writer),
+        }
+    }
+
+    /// Writes the accounts split across `shards` files in `dir` for sharded consumers, each account
+    /// going to `accounts-{client % shards}.csv`. Every shard file is written with its own header,
+    /// even when no accounts land in it, and holds its accounts sorted by Id. The directory must
+    /// already exist and existing shard files are overwritten.
+    pub fn write_accounts_sharded(&self, dir: &Path, shards: usize) -> anyhow::Result<()> {
+        if shards == 0 {
+            return Err(Error::msg("Number of shards must be at least 1"));
+        }
+        let mut sharded: Vec<Vec<AccountWithId>> = vec![Vec::new(); shards];
+        for account in self.retrieve_accounts() {
+            sharded[usize::from(account.id) % shards].push(account);
+        }
+        for (shard, mut accounts) in sharded.into_iter().enumerate() {
+            accounts.sort_by_key(|acct| acct.id);
+            let path = dir.join(format!("accounts-{}.csv", shard));
+            let file = File::create(&path)
+                .with_context(|| format!("Failed to create `{}`", path.display()))?;
+            self.write_selected_accounts(file, accounts)?;
+        }
+        Ok(())
+    }
+
+    /// The header row of account CSV output, with the `no_activity` column when accounts are
+    /// marked.
+    fn accounts_header(&self) -> String {
+        match self.config.no_activity_output {
+            NoActivityOutput::Mark => format!("{},no_activity", OUTPUT_HEADER),
+            _ => OUTPUT_HEADER.to_string(),
+        }
+    }
+
+    /// Whether the account has never had a deposit or withdrawal applied and is empty.
+    fn has_no_activity(&self, account: &AccountWithId) -> bool {
+        !self.funded_clients.contains(&account.id) && account.account == Account::default()
+    }
+
+    /// Every account to output, leaving out those with no activity when they are omitted.
+    fn output_accounts(&self) -> impl Iterator<Item = AccountWithId> + '_ {
+        let omit = self.config.no_activity_output == NoActivityOutput::Omit;
+        self.retrieve_accounts()
+            .filter(move |acct| !omit || !self.has_no_activity(acct))
+    }
+
+    /// Writes a single account row, warning if its displayed balances don't add up. Accounts with
+    /// no activity are skipped or marked as configured.
+    fn write_row<W: Write, E: Write>(
+        &self,
+        mut writer: W,
+        account: &AccountWithId,
+        mut warnings: E,
+    ) -> anyhow::Result<()> {
+        let no_activity = self.has_no_activity(account);
+        if no_activity && self.config.no_activity_output == NoActivityOutput::Omit {
+            return Ok(());
+        }
+        let (available, held, total) = self.formatted_balances(account);
+        if self.config.display_mismatch_warnings
+            && !displayed_sum_matches(&available, &held, &total, Decimal::ZERO)
+        {
+            writeln!(
+                warnings,
+                "Warning: client {} is displayed with available {} and held {} which don't add \
+                 up to total {}",
+                account.id, available, held, total
+            )
+            .context("Failed to write warning")?;
+        }
+        write!(
+            writer,
+            "{},{},{},{},{}",
+            account.id, available, held, total, account.account.locked
+        )
+        .context("Failed to write account")?;
+        if self.config.no_activity_output == NoActivityOutput::Mark {
+            write!(writer, ",{}", no_activity).context("Failed to write account")?;
+        }
+        writeln!(writer).context("Failed to write account")
+    }
+
+    /// Writes all the accounts in CSV format like `write_accounts` but with only the given columns,
+    /// in the order given.
+    pub fn write_accounts_columns<W: Write>(
+        &self,
+        writer: W,
+        columns: &[AccountColumn],
+    ) -> anyhow::Result<()> {
+        let mut writer = self.buffered(writer);
+        let mark = self.config.no_activity_output == NoActivityOutput::Mark;
+        let mut header: Vec<&str> = columns.iter().map(AccountColumn::as_str).collect();
+        if mark {
+            header.push("no_activity");
+        }
+        self.write_header(&mut writer, &header.join(","))?;
+        for account in self.output_accounts() {
+            let (available, held, total) = self.formatted_balances(&account);
+            let mut fields: Vec<String> = columns
+                .iter()
+                .map(|column| match column {
+                    AccountColumn::Client => account.id.to_string(),
+                    AccountColumn::Available => available.clone(),
+                    AccountColumn::Held => held.clone(),
+                    AccountColumn::Total => total.clone(),
+                    AccountColumn::Locked => account.account.locked.to_string(),
+                })
+                .collect();
+            if mark {
+                fields.push(self.has_no_activity(&account).to_string());
+            }
+            writeln!(writer, "{}", fields.join(",")).context("Failed to write account")?;
+        }
+        writer.flush().context("Failed to flush accounts")
+    }
+
+    /// Writes every account to a CSV writer as the record returned by `serialize_fn`, letting the
+    /// caller decide the shape of each record such as adding or renaming columns. The header row
+    /// comes from the record type's field names as usual for `csv::Writer`. Accounts with no
+    /// activity are left out, or marked with a `no_activity` column after the record's own.
+    pub fn write_accounts_with<W, R>(
+        &self,
+        writer: &mut csv::Writer<W>,
+        serialize_fn: impl Fn(&AccountWithId) -> R,
+    ) -> anyhow::Result<()>
+    where
+        W: Write,
+        R: Serialize,
+    {
+        let mark = self.config.no_activity_output == NoActivityOutput::Mark;
+        for account in self.output_accounts() {
+            let record = serialize_fn(&account);
+            if mark {
+                let no_activity = self.has_no_activity(&account);
+                writer.serialize((record, NoActivityColumn { no_activity }))
+            } else {
+                writer.serialize(record)
+            }
+            .context("Failed to write account")?;
+        }
+        writer.flush().context("Failed to flush accounts")
+    }
+
+    /// Every chargeback that locked an account, in the order they happened, along with the state
+    /// of the account at the time it was locked. Accounts locked with a `lock` transaction or
+    /// restored already locked aren't included.
+    pub fn lock_report(&self) -> Vec<LockRecord> {
+        self.lock_records.clone()
+    }
+
+    /// The total balance summed across every locked client account, i.e. the funds frozen by
+    /// chargebacks and locks.
+    pub fn frozen_funds(&self) -> Decimal {
+        self.client_accounts()
+            .filter(|(_, account)| account.locked)
+            .map(|(_, account)| account.total)
+            .sum()
+    }
+
+    /// The total balance summed across every client account, i.e. what is owed to clients. The
+    /// control account only mirrors the clients' funds so isn't included.
+    pub fn total_liabilities(&self) -> Decimal {
+        self.client_accounts()
+            .map(|(_, account)| account.total)
+            .sum()
+    }
+
+    /// Every account except the control account, which only mirrors the clients' funds.
+    fn client_accounts(&self) -> impl Iterator<Item = (u16, Account)> + '_ {
+        let control_account = self.config.control_account;
+        self.accounts
+            .iter()
+            .filter(move |(id, _)| Some(*id) != control_account)
+    }
+
+    /// The median of every client account's total balance, the mean of the middle two when there
+    /// is an even number of accounts. This is `None` when there are no accounts.
+    pub fn median_balance(&self) -> Option<Decimal> {
+        let mut totals: Vec<Decimal> = self.client_accounts().map(|(_, acct)| acct.total).collect();
+        totals.sort_unstable();
+        let mid = totals.len() / 2;
+        match totals.len() {
+            0 => None,
+            len if len % 2 == 1 => Some(totals[mid]),
+            _ => Some((totals[mid - 1] + totals[mid]) / Decimal::TWO),
+        }
+    }
+
+    /// The client with the most funds held and the amount they hold, the lowest Id among any
+    /// clients tied for the most. This is `None` when no account holds any funds.
+    pub fn largest_held(&self) -> Option<(u16, Decimal)> {
+        self.client_accounts()
+            .filter(|(_, account)| account.held > Decimal::ZERO)
+            .map(|(id, account)| (id, account.held))
+            .max_by(|(a_id, a_held), (b_id, b_held)| a_held.cmp(b_held).then(b_id.cmp(a_id)))
+    }
+
+    /// A pseudorandom sample of `n` client accounts, or every one if there are fewer, sorted by Id.
+    /// The same seed always picks the same accounts from the same set of accounts, so a sample
+    /// used to spot-check output can be reproduced.
+    pub fn sample_accounts(&self, n: usize, seed: u64) -> Vec<AccountWithId> {
+        let mut accounts: Vec<AccountWithId> = self
+            .client_accounts()
+            .map(|(id, account)| AccountWithId { id, account })
+            .collect();
+        // Start from a fixed order as the store's order isn't stable
+        accounts.sort_by_key(|acct| acct.id);
+        let n = n.min(accounts.len());
+        let mut rng = SplitMix64::new(seed);
+        // A partial Fisher-Yates shuffle moves the sample to the front
+        for i in 0..n {
+            let j = i + (rng.next_u64() % (accounts.len() - i) as u64) as usize;
+            accounts.swap(i, j);
+        }
+        accounts.truncate(n);
+        accounts.sort_by_key(|acct| acct.id);
+        accounts
+    }
+
+    /// Retrieve the accounts with funds held under dispute, largest held balance first.
+    pub fn accounts_with_held(&self) -> Vec<AccountWithId> {
+        let mut accounts: Vec<AccountWithId> = self
+            .retrieve_accounts()
+            .filter(|acct| !acct.account.held.is_zero())
+            .collect();
+        accounts.sort_by(|a, b| {
+            b.account
+                .held
+                .cmp(&a.account.held)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        accounts
+    }
+
+    /// Writes all the accounts as a JSON array, including any metadata attached to them.
+    pub fn write_accounts_json<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let records: Vec<AccountRecord> = self
+            .output_accounts()
+            .map(|acct| self.account_record(&acct))
+            .collect();
+        let mut writer = self.buffered(writer);
+        serde_json::to_writer(&mut writer, &records).context("Failed to write accounts as JSON")?;
+        writer.flush().context("Failed to flush accounts")
+    }
+
+    /// Writes each account as a JSON object on its own line (JSON Lines) for streaming consumers,
+    /// in the same shape as `write_accounts_json`.
+    pub fn write_accounts_jsonl<W: Write>(&self, writer: W) -> anyhow::Result<()> {
+        let mut writer = self.buffered(writer);
+        for acct in self.output_accounts() {
+            serde_json::to_writer(&mut writer, &self.account_record(&acct))
+                .context("Failed to write account as JSON")?;
+            writeln!(writer).context("Failed to write account as JSON")?;
+        }
+        writer.flush().context("Failed to flush accounts")
+    }
+
+    /// The account in the shape it is output as JSON.
+    fn account_record(&self, acct: &AccountWithId) -> AccountRecord<'_> {
+        let (available, held, total) = self.formatted_balances(acct);
+        AccountRecord {
+            client: acct.id,
+            available,
+            held,
+            total,
+            locked: acct.account.locked,
+            metadata: self.metadata.get(&acct.id),
+            no_activity: (self.config.no_activity_output == NoActivityOutput::Mark)
+                .then(|| self.has_no_activity(acct)),
+        }
+    }
+
+    /// Builds an Arrow record batch with a row per account and `client`, `available`, `held`,
+    /// `total` and `locked` columns, plus `no_activity` when accounts with no activity are
+    /// marked, which the caller can write out as Parquet. Accounts with no activity are left out
+    /// or marked as configured, but the batch otherwise holds the real balances as 128-bit
+    /// decimals at a fixed scale of 4, cut off and rounded like the default CSV output. Output
+    /// scales, zeroed locked display and reconciled total display aren't applied.
+    #[cfg(feature = "arrow")]
+    pub fn export_arrow(&self) -> arrow_array::RecordBatch {
+        use arrow_array::{ArrayRef, BooleanArray, Decimal128Array, UInt16Array};
+        use arrow_schema::{DataType, Field, Schema};
+        use rust_decimal::RoundingStrategy;
+        use std::sync::Arc;
+
+        let accounts: Vec<AccountWithId> = self.output_accounts().collect();
+        let decimal_column = |balance: fn(&Account) -> Decimal| -> ArrayRef {
+            let values = accounts.iter().map(|acct| {
+                let mut value = balance(&acct.account);
+                value.rescale(4);
+                value.mantissa()
+            });
+            Arc::new(
+                Decimal128Array::from_iter_values(values)
+                    .with_precision_and_scale(38, 4)
+                    .expect("Invalid decimal precision and scale"),
+            )
+        };
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt16Array::from_iter_values(
+                accounts.iter().map(|acct| acct.id),
+            )),
+            decimal_column(|account| {
+                account
+                    .available
+                    .round_dp_with_strategy(4, RoundingStrategy::ToZero)
+            }),
+            decimal_column(|account| {
+                account
+                    .held
+                    .round_dp_with_strategy(4, RoundingStrategy::ToZero)
+            }),
+            decimal_column(|account| account.total.round_dp(4)),
+            Arc::new(BooleanArray::from(
+                accounts
+                    .iter()
+                    .map(|acct| acct.account.locked)
+                    .collect::<Vec<bool>>(),
+            )),
+        ];
+        let mut fields = vec![
+            Field::new("client", DataType::UInt16, false),
+            Field::new("available", DataType::Decimal128(38, 4), false),
+            Field::new("held", DataType::Decimal128(38, 4), false),
+            Field::new("total", DataType::Decimal128(38, 4), false),
+            Field::new("locked", DataType::Boolean, false),
+        ];
+        if self.config.no_activity_output == NoActivityOutput::Mark {
+            columns.push(Arc::new(BooleanArray::from(
+                accounts
+                    .iter()
+                    .map(|acct| self.has_no_activity(acct))
+                    .collect::<Vec<bool>>(),
+            )));
+            fields.push(Field::new("no_activity", DataType::Boolean, false));
+        }
+        arrow_array::RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+            .expect("Account columns don't match the schema")
+    }
+
+    /// Binds to `addr` and serves a single connection with `serve_tcp_listener`, turning the
+    /// engine into a simple network service in place of reading transactions from a file.
+    #[cfg(feature = "tcp")]
+    pub fn serve_stdin_tcp<A: std::net::ToSocketAddrs>(&mut self, addr: A) -> anyhow::Result<()> {
+        let listener = std::net::TcpListener::bind(addr).context("Failed to bind listener")?;
+        self.serve_tcp_listener(&listener)
+    }
+
+    /// Accepts a single connection on the listener and serves it until it closes or sends `quit`.
+    /// Every other line is either a transaction in the input CSV format without a header, which
+    /// is processed and answered with `ok` or `error: <message>`, or `accounts`, which is answered
+    /// with the current accounts in the output CSV format followed by an empty line. A failed
+    /// transaction is always answered with an error, whatever the error policy.
+    #[cfg(feature = "tcp")]
+    pub fn serve_tcp_listener(&mut self, listener: &std::net::TcpListener) -> anyhow::Result<()> {
+        let (stream, _) = listener.accept().context("Failed to accept connection")?;
+        let mut writer = stream.try_clone().context("Failed to clone connection")?;
+        for line in BufReader::new(stream).lines() {
+            let line = line.context("Failed to read from connection")?;
+            match line.trim() {
+                "" => continue,
+                "quit" => break,
+                "accounts" => {
+                    self.write_accounts(&mut writer)?;
+                    writeln!(writer).context("Failed to write to connection")?;
+                }
+                tx_line => {
+                    let input = format!("{}\n{}", INPUT_HEADER, tx_line);
+                    let res = self
+                        .processor(input.as_bytes())
+                        .and_then(|mut processor| processor.process_next().unwrap_or(Ok(())));
+                    match res {
+                        Ok(()) => writeln!(writer, "ok"),
+                        Err(err) => writeln!(writer, "error: {:#}", err),
+                    }
+                    .context("Failed to write to connection")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every client account's output adds up, i.e. that the available and held
+    /// balances as displayed sum to the total as displayed, and splits the funds in locked accounts
+    /// from those in active ones. Sums that differ from the total by no more than
+    /// `tolerance` are accepted so rounding below the precision that matters isn't flagged.
+    pub fn reconcile(&self, tolerance: Decimal) -> ReconciliationReport {
+        let mut report = ReconciliationReport::default();
+        for (id, account) in self.client_accounts() {
+            let acct = AccountWithId { id, account };
+            report.accounts += 1;
+            if acct.account.locked {
+                report.frozen_total += acct.account.total;
+            } else {
+                report.active_total += acct.account.total;
+            }
+            let (available, held, total) = self.formatted_balances(&acct);
+            if !displayed_sum_matches(&available, &held, &total, tolerance) {
+                report.display_mismatches.push(acct.id);
+            }
+        }
+        report.display_mismatches.sort_unstable();
+        report
+    }
+}
+
+/// Whether the displayed available and held balances sum to within `tolerance` of the displayed
+/// total.
+fn displayed_sum_matches(available: &str, held: &str, total: &str, tolerance: Decimal) -> bool {
+    let parse = |value: &str| Decimal::from_str(value).unwrap_or_default();
+    (parse(available) + parse(held) - parse(total)).abs() <= tolerance
+}
+
+/// Processes a CSV source one record at a time on behalf of a `TransactionEngine`. While paused
+/// no further records are pulled from the source and resuming carries on from the same position.
+pub struct ReaderProcessor<'a, R: Read, S: AccountStore = HashMap<u16, Account>> {
+    engine: &'a mut TransactionEngine<S>,
+    records: csv::StringRecordsIntoIter<R>,
+    headers: StringRecord,
+    // The index of the `type` column, which always exists once the header has been checked
+    type_column: Option<usize>,
+    // The index of the `tx` column, which also always exists once the header has been checked
+    tx_column: Option<usize>,
+    // The number of records pulled from the source so far
+    rows: usize,
+    // The client Id, once remapped, of the last record that could be read as a transaction
+    last_client: Option<u16>,
+    paused: bool,
+    finished: bool,
+}
+
+impl<'a, R: Read, S: AccountStore> ReaderProcessor<'a, R, S> {
+    /// Processes the next record, returning `false` without pulling anything from the source if
+    /// processing is paused or the source is exhausted. Failures are handled by the engine's
+    /// error policy.
+    pub fn step(&mut self) -> anyhow::Result<bool> {
+        if self.paused || self.finished {
+            return Ok(false);
+        }
+        match self.process_next() {
+            Some(Err(err)) => self.engine.handle_error(self.rows, err)?,
+            Some(Ok(())) => {}
+            None => {
+                self.finished = true;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Processes the next record, returning how it went without involving the error policy, or
+    /// `None` if the source is exhausted.
+    fn process_next(&mut self) -> Option<anyhow::Result<()>> {
+        let record_res = self.records.next()?;
+        self.rows += 1;
+        let tx_res = self.read_transaction(record_res);
+        let fault = self
+            .engine
+            .fault_injector
+            .as_mut()
+            .and_then(FaultInjector::next_fault);
+        let res = match (fault, tx_res) {
+            // An injected fault takes the place of the record
+            (Some(fault), _) => Err(fault.into()),
+            (None, Ok(tx)) => {
+                let client_map = &self.engine.config.client_id_map;
+                self.last_client = Some(*client_map.get(&tx.client_id).unwrap_or(&tx.client_id));
+                self.engine
+                    .process_transaction(tx)
+                    .context("Failed to process transaction")
+            }
+            (None, Err(err)) => Err(err.into()),
+        };
+        Some(res)
+    }
+
+    /// Reads a record pulled from the source as a transaction, mapping any aliased type.
+    fn read_transaction(
+        &self,
+        record_res: csv::Result<StringRecord>,
+    ) -> Result<Transaction, EngineError> {
+        record_res
+            .map_err(|err| EngineError::Deserialize(err.to_string()))
+            .and_then(|record| {
+                if self.is_missing_tx_id(&record) {
+                    return Err(EngineError::MissingTxId);
+                }
+                self.with_canonical_type(record)
+                    .deserialize::<Transaction>(Some(&self.headers))
+                    .map_err(|err| EngineError::Deserialize(err.to_string()))
+            })
+    }
+
+    /// Reads the whole source then reverses its records last first with
+    /// `TransactionEngine::undo_transaction`, returning the number reversed. Failures are handled
+    /// by the engine's error policy.
+    fn undo_all(&mut self) -> anyhow::Result<usize> {
+        let mut transactions = Vec::new();
+        while let Some(record_res) = self.records.next() {
+            self.rows += 1;
+            match self.read_transaction(record_res) {
+                Ok(tx) => transactions.push((self.rows, tx)),
+                Err(err) => self.engine.handle_error(self.rows, err.into())?,
+            }
+        }
+        self.finished = true;
+        let mut reversed = 0;
+        for (row, tx) in transactions.iter().rev() {
+            match self.engine.undo_transaction(tx) {
+                Ok(true) => reversed += 1,
+                Ok(false) => {}
+                Err(err) => self.engine.handle_error(*row, err)?,
+            }
+        }
+        Ok(reversed)
+    }
+
+    /// Whether the record's `tx` field is empty, which would otherwise fail to deserialize with an
+    /// unhelpful error.
+    fn is_missing_tx_id(&self, record: &StringRecord) -> bool {
+        self.tx_column
+            .and_then(|tx_column| record.get(tx_column))
+            .is_some_and(|tx_id| tx_id.trim().is_empty())
+    }
+
+    /// Replaces an aliased transaction type in the record with the canonical type name.
+    fn with_canonical_type(&self, record: StringRecord) -> StringRecord {
+        let aliases = &self.engine.config.type_aliases;
+        let type_column = match self.type_column {
+            Some(type_column) if !aliases.is_empty() => type_column,
+            _ => return record,
+        };
+        match record
+            .get(type_column)
+            .and_then(|tx_type| aliases.get(tx_type))
+        {
+            Some(tx_type) => record
+                .iter()
+                .enumerate()
+                .map(|(idx, field)| {
+                    if idx == type_column {
+                        tx_type.as_str()
+                    } else {
+                        field
+                    }
+                })
+                .collect(),
+            None => record,
+        }
+    }
+
+    /// Processes records until processing is paused or the source is exhausted.
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Stops any further records being pulled from the source until `resume` is called.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Whether every record in the source has been processed.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The number of records pulled from the source so far.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// The engine being processed into, for inspecting its state mid-stream.
+    pub fn engine(&self) -> &TransactionEngine<S> {
+        self.engine
+    }
+}
+
+#[cfg(test)]
+// The original flow tests compare against literal bools
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use crate::engine::TransactionType::Chargeback;
+    use crate::engine::TransactionType::Deposit;
+    use crate::engine::TransactionType::Dispute;
+    use crate::engine::TransactionType::Resolve;
+    use crate::engine::TransactionType::Withdrawal;
+    use proptest::prelude::*;
+    use rust_decimal::prelude::FromStr;
+
+    fn dec(value: &str) -> Decimal {
+        Decimal::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn can_deposit_and_withdraw() {
+        let mut engine = TransactionEngine::new();
+        let acct_id = 1;
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.available, dec("1.0"));
+        engine
+            .process_transaction(Transaction::from(Withdrawal, acct_id, 1, Some("0.1234")))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.available, dec("0.8766"));
+    }
+
+    #[test]
+    fn chargeback_deposit_flow() {
+        let mut engine = TransactionEngine::new();
+        let acct_id = 1;
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 1, Option::<&str>::None))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        // Available and held should have been modified due to the dispute
+        assert_eq!(current_acct.available, dec("0"));
+        assert_eq!(current_acct.held, dec("1.0"));
+        assert_eq!(engine.disputed_transactions.contains(&1), true);
+        engine
+            .process_transaction(Transaction::from(
+                Chargeback,
+                acct_id,
+                1,
+                Option::<&str>::None,
+            ))
+            .unwrap();
+        // Now that a chargeback has occurred the account should be empty and locked
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.available, dec("0"));
+        assert_eq!(current_acct.held, dec("0"));
+        assert_eq!(current_acct.locked, true);
+        assert_eq!(engine.disputed_transactions.is_empty(), true);
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 2, Some("1.0")))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        // Since we are locked we shouldn't be able to deposit anymore
+        assert_eq!(current_acct.total, dec("0"));
+    }
+
+    #[test]
+    fn resolve_deposit_flow() {
+        let mut engine = TransactionEngine::new();
+        let acct_id = 1;
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 1, Option::<&str>::None))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        // Available and held should have been modified due to the dispute
+        assert_eq!(current_acct.available, dec("0"));
+        assert_eq!(current_acct.held, dec("1.0"));
+        assert_eq!(engine.disputed_transactions.contains(&1), true);
+        engine
+            .process_transaction(Transaction::from(Resolve, acct_id, 1, Option::<&str>::None))
+            .unwrap();
+        // Now that a resolve has occurred the account should have funds restored
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.available, dec("1.0"));
+        assert_eq!(current_acct.held, dec("0"));
+        assert_eq!(current_acct.locked, false);
+        assert_eq!(engine.disputed_transactions.is_empty(), true);
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 2, Some("1.0")))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        // Additional deposits should be fine
+        assert_eq!(current_acct.available, dec("2.0"));
+    }
+
+    #[test]
+    fn resolve_withdrawal_flow() {
+        let mut engine = TransactionEngine::new();
+        let acct_id = 1;
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Withdrawal, acct_id, 2, Some("1.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 2, Option::<&str>::None))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        // Available and held should have been modified due to the dispute
+        assert_eq!(current_acct.available, dec("0"));
+        assert_eq!(current_acct.held, dec("1.0"));
+        assert_eq!(current_acct.total, dec("1.0"));
+        assert_eq!(engine.disputed_transactions.contains(&2), true);
+        engine
+            .process_transaction(Transaction::from(Resolve, acct_id, 2, Option::<&str>::None))
+            .unwrap();
+        // Now that a resolve has occurred the account should have funds restored
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.available, dec("0"));
+        assert_eq!(current_acct.held, dec("0"));
+        assert_eq!(current_acct.locked, false);
+        assert_eq!(engine.disputed_transactions.is_empty(), true);
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 3, Some("1.0")))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        // Additional deposits should be fine
+        assert_eq!(current_acct.available, dec("1.0"));
+    }
+
+    #[test]
+    fn withdraw_too_much() {
+        let mut engine = TransactionEngine::new();
+        let acct_id = 1;
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Withdrawal, acct_id, 1, Some("2.0")))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        // The withdrawal should not have had an effect
+        assert_eq!(current_acct.available, dec("1.0"));
+    }
+
+    #[test]
+    fn balances_retain_full_precision() {
+        let mut engine = TransactionEngine::new();
+        let acct_id = 1;
+        let mut expected = Decimal::ZERO;
+        for tx_id in 1..=1000 {
+            let amount = format!("0.{:08}", 12345678 + tx_id);
+            expected += dec(&amount);
+            engine
+                .process_transaction(Transaction::from(Deposit, acct_id, tx_id, Some(amount)))
+                .unwrap();
+            // Every intermediate balance must match the exact running sum
+            let current_acct = engine.accounts.get(&acct_id).unwrap();
+            assert_eq!(current_acct.total, expected);
+            assert_eq!(current_acct.available, expected);
+        }
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 1, Option::<&str>::None))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.held, dec("0.12345679"));
+        assert_eq!(current_acct.available + current_acct.held, expected);
+        // Nothing was lost to rounding, the scale of the stored total is the full input scale
+        assert_eq!(current_acct.total.scale(), 8);
+        assert_ne!(current_acct.total, current_acct.total.round_dp(4));
+    }
+
+    #[test]
+    fn account_metadata_in_json_output() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("1.5")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 2, 2, Some("2.0")))
+            .unwrap();
+        engine.set_account_metadata(1, "region", "eu-west".to_string());
+        engine.set_account_metadata(1, "tier", "gold".to_string());
+        engine.set_account_metadata(1, "tier", "platinum".to_string());
+        assert_eq!(engine.account_metadata(1, "region"), Some("eu-west"));
+        assert_eq!(engine.account_metadata(1, "tier"), Some("platinum"));
+        assert_eq!(engine.account_metadata(1, "missing"), None);
+        assert_eq!(engine.account_metadata(2, "region"), None);
+
+        let mut output = Vec::new();
+        engine.write_accounts_json(&mut output).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let accounts = json.as_array().unwrap();
+        assert_eq!(accounts.len(), 2);
+        let first = accounts.iter().find(|acct| acct["client"] == 1).unwrap();
+        assert_eq!(first["available"], "1.5000");
+        assert_eq!(first["metadata"]["region"], "eu-west");
+        assert_eq!(first["metadata"]["tier"], "platinum");
+        // Accounts without metadata don't carry the field at all
+        let second = accounts.iter().find(|acct| acct["client"] == 2).unwrap();
+        assert_eq!(second["total"], "2.0000");
+        assert!(second.get("metadata").is_none());
+    }
+
+    #[test]
+    fn empty_input_is_missing_header() {
+        let mut engine = TransactionEngine::new();
+        let err = engine.process_reader("".as_bytes()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::MissingHeader)
+        );
+        // A file which starts straight into records has no header either
+        let err = engine
+            .process_reader("deposit,1,1,1.0\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::MissingHeader)
+        );
+        assert!(engine.accounts.is_empty());
+    }
+
+    #[test]
+    fn header_only_input_has_no_accounts() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_reader("type,client,tx,amount\n".as_bytes())
+            .unwrap();
+        assert_eq!(engine.retrieve_accounts().count(), 0);
+        engine
+            .process_reader("type,client,tx,amount".as_bytes())
+            .unwrap();
+        assert_eq!(engine.retrieve_accounts().count(), 0);
+    }
+
+    #[test]
+    fn process_and_diff_reports_net_changes() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("10.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 2, 2, Some("5.0")))
+            .unwrap();
+        let diffs = engine
+            .process_and_diff(vec![
+                Transaction::new(Deposit, 1, 3, Some(dec("2.5"))),
+                Transaction::new(Withdrawal, 1, 4, Some(dec("1.0"))),
+                Transaction::new(Dispute, 2, 2, None),
+                Transaction::new(Chargeback, 2, 2, None),
+                Transaction::new(Deposit, 3, 5, Some(dec("7.0"))),
+                Transaction::new(Withdrawal, 3, 6, Some(dec("7.0"))),
+            ])
+            .unwrap();
+        assert_eq!(diffs.len(), 3);
+        assert_eq!(
+            diffs[&1],
+            AccountDiff {
+                id: 1,
+                available: dec("1.5"),
+                held: dec("0"),
+                total: dec("1.5"),
+                locked: None,
+            }
+        );
+        assert_eq!(
+            diffs[&2],
+            AccountDiff {
+                id: 2,
+                available: dec("-5.0"),
+                held: dec("0"),
+                total: dec("-5.0"),
+                locked: Some(true),
+            }
+        );
+        // A new client whose balance nets out is still reported as touched
+        assert_eq!(
+            diffs[&3],
+            AccountDiff::between(3, &Account::default(), &Account::default())
+        );
+    }
+
+    #[test]
+    fn min_deposit_rejects_small_deposits() {
+        let mut engine = TransactionEngine::new().with_min_deposit(dec("10.0"));
+        let acct_id = 1;
+        let err = engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("9.9999")))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::BelowMinimumDeposit {
+                amount: dec("9.9999"),
+                minimum: dec("10.0"),
+            })
+        );
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.total, dec("0"));
+        // The rejected deposit was not retained so it can't be disputed
+        assert!(!engine.transactions.contains_key(&1));
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 2, Some("10.0")))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.total, dec("10.0"));
+    }
+
+    #[test]
+    fn only_changed_accounts_are_emitted_against_baseline() {
+        let baseline = "client,available,held,total,locked\n\
+            1,10.0000,0.0000,10.0000,false\n\
+            2,5.0000,1.0000,6.0000,false\n\
+            3,3.0000,0.0000,3.0000,true\n";
+        let mut engine = TransactionEngine::from_accounts_csv(baseline.as_bytes()).unwrap();
+        let current_acct = engine.accounts.get(&2).unwrap();
+        assert_eq!(current_acct.held, dec("1.0"));
+        let input = "type,client,tx,amount\n\
+            deposit,2,1,1.5\n\
+            deposit,3,2,1.0\n\
+            deposit,4,3,2.0\n\
+            withdrawal,1,4,20.0\n";
+        let changed = engine.process_reader_changed(input.as_bytes()).unwrap();
+        // Client 1's withdrawal failed and client 3 is locked so neither changed
+        let ids: Vec<u16> = changed.iter().map(|acct| acct.id).collect();
+        assert_eq!(ids, vec![2, 4]);
+        assert_eq!(changed[0].account.available, dec("6.5"));
+        assert_eq!(changed[0].account.total, dec("7.5"));
+        assert_eq!(changed[1].account.total, dec("2.0"));
+    }
+
+    /// A step of a randomly generated run against the engine. Disputes, resolves and chargebacks
+    /// pick one of the deposits or withdrawals issued so far by index (modulo the number issued)
+    /// and always use the client that owns it.
+    #[derive(Debug, Clone)]
+    enum Step {
+        Deposit(u16, Decimal),
+        Withdrawal(u16, Decimal),
+        Dispute(usize),
+        Resolve(usize),
+        Chargeback(usize),
+    }
+
+    fn arb_amount() -> impl Strategy<Value = Decimal> {
+        (1i64..1_000_000, 0u32..=4).prop_map(|(mantissa, scale)| Decimal::new(mantissa, scale))
+    }
+
+    fn arb_step() -> impl Strategy<Value = Step> {
+        prop_oneof![
+            (1u16..=4, arb_amount()).prop_map(|(client, amount)| Step::Deposit(client, amount)),
+            (1u16..=4, arb_amount()).prop_map(|(client, amount)| Step::Withdrawal(client, amount)),
+            any::<usize>().prop_map(Step::Dispute),
+            any::<usize>().prop_map(Step::Resolve),
+            any::<usize>().prop_map(Step::Chargeback),
+        ]
+    }
+
+    fn assert_invariants(engine: &TransactionEngine) -> Result<(), TestCaseError> {
+        for (id, acct) in &engine.accounts {
+            prop_assert_eq!(
+                acct.available + acct.held,
+                acct.total,
+                "available + held != total for client {}",
+                id
+            );
+            prop_assert!(
+                acct.held >= Decimal::ZERO,
+                "held went negative for client {}",
+                id
+            );
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn dispute_state_machine_invariants(steps in prop::collection::vec(arb_step(), 1..200)) {
+            let mut engine = TransactionEngine::new();
+            // Every deposit and withdrawal issued so far as (client Id, transaction Id)
+            let mut issued: Vec<(u16, TxId)> = Vec::new();
+            for step in steps {
+                let tx = match step {
+                    Step::Deposit(client, amount) | Step::Withdrawal(client, amount) => {
+                        let tx_id = issued.len() as TxId + 1;
+                        issued.push((client, tx_id));
+                        let tx_type = match step {
+                            Step::Deposit(..) => Deposit,
+                            _ => Withdrawal,
+                        };
+                        Transaction::new(tx_type, client, tx_id, Some(amount))
+                    }
+                    Step::Dispute(idx) | Step::Resolve(idx) | Step::Chargeback(idx) => {
+                        if issued.is_empty() {
+                            continue;
+                        }
+                        let (client, tx_id) = issued[idx % issued.len()];
+                        let tx_type = match step {
+                            Step::Dispute(_) => Dispute,
+                            Step::Resolve(_) => Resolve,
+                            _ => Chargeback,
+                        };
+                        Transaction::new(tx_type, client, tx_id, None)
+                    }
+                };
+                engine.process_transaction(tx).unwrap();
+                assert_invariants(&engine)?;
+            }
+        }
+    }
+
+    #[test]
+    fn accounts_in_insertion_order() {
+        let mut engine = TransactionEngine::new();
+        for (tx_id, client_id) in [7, 3, 7, 12, 1, 3].iter().enumerate() {
+            engine
+                .process_transaction(Transaction::from(
+                    Deposit,
+                    *client_id,
+                    tx_id as TxId,
+                    Some("1.0"),
+                ))
+                .unwrap();
+        }
+        let ids: Vec<u16> = engine
+            .retrieve_accounts_insertion_order()
+            .iter()
+            .map(|acct| acct.id)
+            .collect();
+        assert_eq!(ids, vec![7, 3, 12, 1]);
+        assert_eq!(
+            engine.retrieve_accounts_insertion_order()[0].account.total,
+            dec("2.0")
+        );
+    }
+
+    #[test]
+    fn dust_held_is_snapped_to_zero() {
+        let mut engine = TransactionEngine::new().with_held_dust_epsilon(dec("0.0001"));
+        let acct_id = 1;
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 2, Some("0.00001")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 1, Option::<&str>::None))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 2, Option::<&str>::None))
+            .unwrap();
+        // A legitimately disputed dust amount stays held while its dispute is open
+        engine
+            .process_transaction(Transaction::from(Resolve, acct_id, 1, Option::<&str>::None))
+            .unwrap();
+        let current_acct = engine.accounts.get_mut(&acct_id).unwrap();
+        assert_eq!(current_acct.held, dec("0.00001"));
+        // Leave dust behind that no dispute accounts for, as a partial chargeback might
+        current_acct.held += dec("0.00002");
+        current_acct.total += dec("0.00002");
+        engine
+            .process_transaction(Transaction::from(Resolve, acct_id, 2, Option::<&str>::None))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.held, dec("0"));
+        assert_eq!(current_acct.available, dec("1.00003"));
+        assert_eq!(
+            current_acct.available + current_acct.held,
+            current_acct.total
+        );
+    }
+
+    #[test]
+    fn collected_errors_as_json() {
+        let mut engine = TransactionEngine::new()
+            .with_min_deposit(dec("1.0"))
+            .with_error_policy(ErrorPolicy::Collect);
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5.0\n\
+            deposit,1,,1.0\n\
+            deposit,1,2,0.5\n\
+            withdrawal,1,3,\n\
+            deposit,1,4,2.0\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+        // The good rows either side of the bad ones were still processed
+        let current_acct = engine.accounts.get(&1).unwrap();
+        assert_eq!(current_acct.total, dec("7.0"));
+        assert_eq!(engine.errors().len(), 3);
+        assert_eq!(
+            engine.errors()[1],
+            (
+                3,
+                EngineError::BelowMinimumDeposit {
+                    amount: dec("0.5"),
+                    minimum: dec("1.0"),
+                }
+            )
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&engine.errors_as_json()).unwrap();
+        let errors = json.as_array().unwrap();
+        let rows: Vec<&serde_json::Value> = errors.iter().map(|err| &err["row"]).collect();
+        assert_eq!(rows, vec![2, 3, 4]);
+        let kinds: Vec<&serde_json::Value> = errors.iter().map(|err| &err["kind"]).collect();
+        assert_eq!(
+            kinds,
+            vec!["MissingTxId", "BelowMinimumDeposit", "Processing"]
+        );
+        assert!(errors[2]["message"]
+            .as_str()
+            .unwrap()
+            .contains("Amount was empty"));
+    }
+
+    #[test]
+    fn missing_tx_id() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5.0\n\
+            deposit,1,,1.0\n\
+            deposit,1,2,2.0\n";
+        let mut engine = TransactionEngine::new();
+        let err = engine.process_reader(input.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::MissingTxId)
+        );
+
+        let mut engine = TransactionEngine::new().with_error_policy(ErrorPolicy::Collect);
+        engine.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(engine.errors(), &[(2, EngineError::MissingTxId)]);
+        assert_eq!(engine.account(1).unwrap().total(), dec("7.0"));
+    }
+
+    #[test]
+    fn skipped_errors_are_not_collected() {
+        let mut engine = TransactionEngine::new().with_error_policy(ErrorPolicy::Skip);
+        let input = "type,client,tx,amount\n\
+            deposit,1,,1.0\n\
+            deposit,1,1,2.0\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("2.0"));
+        assert!(engine.errors().is_empty());
+        assert_eq!(engine.errors_as_json(), "[]");
+    }
+
+    #[test]
+    fn retransmitted_dispute_messages_are_ignored() {
+        let mut engine = TransactionEngine::new().with_dispute_dedup_window(2);
+        let acct_id = 1;
+        let none = Option::<&str>::None;
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 2, Some("2.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 1, none))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 1, none))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.held, dec("1.0"));
+        engine
+            .process_transaction(Transaction::from(Resolve, acct_id, 1, none))
+            .unwrap();
+        // Without the window this retransmit would put the resolved transaction back in dispute
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 1, none))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.held, dec("0"));
+        assert_eq!(current_acct.available, dec("3.0"));
+        assert!(engine.disputed_transactions.is_empty());
+
+        // Once the original dispute falls out of the window it is processed again
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 2, none))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Resolve, acct_id, 2, none))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 1, none))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.held, dec("1.0"));
+
+        // A dispute which wasn't applied isn't remembered, so a retry once it can apply goes ahead
+        let mut engine = TransactionEngine::new().with_dispute_dedup_window(2);
+        engine
+            .process_lines(&["dispute,1,1,", "deposit,1,1,1.0", "dispute,1,1,"])
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.held, dec("1.0"));
+        assert_eq!(current_acct.available, dec("0"));
+    }
+
+    #[test]
+    fn process_with_offsets_returns_last_good_offset() {
+        let mut engine = TransactionEngine::new();
+        let offset = engine.process_with_offsets(vec![
+            (100, Transaction::from(Deposit, 1, 1, Some("1.0"))),
+            (101, Transaction::from(Deposit, 1, 2, Some("2.0"))),
+            (102, Transaction::from(Withdrawal, 1, 3, Some("0.5"))),
+        ]);
+        assert_eq!(offset.unwrap(), Some(102));
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("2.5"));
+
+        // Processing stops at a failure, which is returned so the consumer doesn't commit past it
+        let err = engine
+            .process_with_offsets(vec![
+                (103, Transaction::from(Deposit, 1, 4, Some("1.0"))),
+                (104, Transaction::from(Deposit, 1, 5, Some("bad"))),
+                (105, Transaction::from(Deposit, 1, 6, Some("1.0"))),
+            ])
+            .unwrap_err();
+        assert!(format!("{:#}", err).starts_with("Failed at offset 104"));
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("3.5"));
+        assert_eq!(engine.process_with_offsets(Vec::new()).unwrap(), None);
+
+        // When failures are skipped the offsets after them are still reached
+        let mut engine = TransactionEngine::new().with_error_policy(ErrorPolicy::Collect);
+        let offset = engine.process_with_offsets(vec![
+            (u64::MAX - 1, Transaction::from(Deposit, 1, 1, Some("bad"))),
+            (u64::MAX, Transaction::from(Deposit, 1, 2, Some("1.0"))),
+        ]);
+        assert_eq!(offset.unwrap(), Some(u64::MAX));
+        assert_eq!(engine.errors().len(), 1);
+        assert_eq!(engine.errors()[0].0, 1);
+    }
+
+    #[test]
+    fn dispute_amounts_are_ignored() {
+        let mut engine = TransactionEngine::new();
+        let acct_id = 1;
+        engine
+            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, acct_id, 1, Some("nonsense")))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.held, dec("1.0"));
+        engine
+            .process_transaction(Transaction::from(Resolve, acct_id, 1, Some("50.0")))
+            .unwrap();
+        let current_acct = engine.accounts.get(&acct_id).unwrap();
+        assert_eq!(current_acct.held, dec("0"));
+        assert_eq!(current_acct.available, dec("1.0"));
+        assert_eq!(engine.ignored_amounts(), 2);
+        // The amount on a dispute can't be read even by accident
+        assert!(Transaction::from(Chargeback, acct_id, 1, Some("1.0"))
+            .amount()
+            .is_err());
+    }
+
+    #[test]
+    fn timed_processing_counts_rows() {
+        let mut engine = TransactionEngine::new();
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx_id in 1..=250 {
+            input.push_str(&format!("deposit,{},{},1.0\n", tx_id % 10, tx_id));
+        }
+        let (rows, elapsed) = engine.process_reader_timed(input.as_bytes()).unwrap();
+        assert_eq!(rows, 250);
+        assert!(elapsed > Duration::ZERO);
+        assert_eq!(engine.retrieve_accounts().count(), 10);
+    }
+
+    #[test]
+    fn per_column_output_scales() {
+        let mut engine = TransactionEngine::new().with_output_scales(OutputScales {
+            available: 3,
+            held: 2,
+            total: 4,
+        });
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("1.23456")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 2, Some("2.5")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, 1, 2, Option::<&str>::None))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 2, 3, Some("3.0")))
+            .unwrap();
+        let mut output = Vec::new();
+        engine
+            .write_selected_accounts(&mut output, engine.retrieve_accounts_insertion_order())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+            1,1.234,2.50,3.7346,false\n\
+            2,3.000,0.00,3.0000,false\n"
+        );
+        // Client 1's displayed columns no longer add up due to the differing scales
+        let report = engine.reconcile(Decimal::ZERO);
+        assert_eq!(report.accounts, 2);
+        assert_eq!(report.display_mismatches, vec![1]);
+        // At the default scales the same engine's output is unchanged
+        let engine = TransactionEngine {
+            config: EngineConfig::default(),
+            ..engine
+        };
+        let mut output = Vec::new();
+        engine.write_accounts(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("1,1.2345,2.5000,3.7346,false\n"));
+    }
+
+    #[test]
+    fn reconcile_within_tolerance() {
+        let mut engine = TransactionEngine::new().with_output_scales(OutputScales {
+            available: 3,
+            held: 2,
+            total: 4,
+        });
+        engine
+            .process_lines(&[
+                "deposit,1,1,1.23456",
+                "deposit,2,2,1.2",
+                "deposit,2,3,0.0059",
+            ])
+            .unwrap();
+        // Client 1 is displayed 0.0006 out and client 2 0.0009 out
+        let report = engine.reconcile(dec("0.0006"));
+        assert_eq!(report.accounts, 2);
+        assert_eq!(report.display_mismatches, vec![2]);
+        let report = engine.reconcile(dec("0.001"));
+        assert!(report.display_mismatches.is_empty());
+    }
+
+    #[test]
+    fn small_output_buffer() {
+        let lines: Vec<String> = (1..=20)
+            .map(|id| format!("deposit,{},{},{}.5", id, id, id))
+            .collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut engine = TransactionEngine::new();
+        engine.process_lines(&lines).unwrap();
+        let mut expected = Vec::new();
+        engine.write_accounts(&mut expected).unwrap();
+
+        // A buffer smaller than a row both buffers pieces of rows and is bypassed by larger ones
+        let engine = engine.with_output_buffer_size(4);
+        let mut output = Vec::new();
+        engine.write_accounts(&mut output).unwrap();
+        assert_eq!(output, expected);
+        assert_eq!(String::from_utf8(output).unwrap().lines().count(), 21);
+    }
+
+    #[test]
+    fn reconcile_splits_frozen_funds() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,1,2,2.5",
+                "deposit,2,3,4.0",
+                "dispute,1,2,",
+                "chargeback,1,2,",
+            ])
+            .unwrap();
+        let report = engine.reconcile(Decimal::ZERO);
+        assert_eq!(report.accounts, 2);
+        assert_eq!(report.active_total, dec("4.0"));
+        assert_eq!(report.frozen_total, dec("5.0"));
+        assert_eq!(report.frozen_total, engine.frozen_funds());
+    }
+
+    #[test]
+    fn pause_and_resume_processing() {
+        let mut engine = TransactionEngine::new();
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1.0\n\
+            deposit,1,2,2.0\n\
+            withdrawal,1,3,0.5\n\
+            deposit,2,4,4.0\n";
+        let mut processor = engine.processor(input.as_bytes()).unwrap();
+        assert!(processor.step().unwrap());
+        assert!(processor.step().unwrap());
+        processor.pause();
+        // Nothing is pulled from the source while paused
+        assert!(!processor.step().unwrap());
+        processor.run().unwrap();
+        assert_eq!(processor.rows(), 2);
+        assert!(!processor.is_finished());
+        let current_acct = processor.engine().accounts.get(&1).unwrap();
+        assert_eq!(current_acct.total, dec("3.0"));
+        assert!(!processor.engine().accounts.contains_key(&2));
+
+        processor.resume();
+        processor.run().unwrap();
+        assert_eq!(processor.rows(), 4);
+        assert!(processor.is_finished());
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("2.5"));
+        assert_eq!(engine.accounts.get(&2).unwrap().total, dec("4.0"));
+    }
+
+    #[test]
+    fn accounts_over_held_ratio_are_flagged() {
+        let mut engine = TransactionEngine::new().with_max_held_ratio(dec("0.5"));
+        let none = Option::<&str>::None;
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("4.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 2, Some("6.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 2, 3, Some("5.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 2, 4, Some("5.0")))
+            .unwrap();
+        // Exactly half held is not over the ratio
+        engine
+            .process_transaction(Transaction::from(Dispute, 2, 3, none))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, 1, 1, none))
+            .unwrap();
+        assert!(engine.held_ratio_flagged().is_empty());
+        engine
+            .process_transaction(Transaction::from(Dispute, 1, 2, none))
+            .unwrap();
+        assert_eq!(engine.held_ratio_flagged(), vec![1]);
+        // The flag sticks once the disputes are resolved
+        engine
+            .process_transaction(Transaction::from(Resolve, 1, 2, none))
+            .unwrap();
+        assert_eq!(engine.held_ratio_flagged(), vec![1]);
+    }
+
+    #[test]
+    fn process_transaction_by_reference() {
+        let mut engine = TransactionEngine::new();
+        let deposit = Transaction::from(Deposit, 1, 1, Some("2.0"));
+        engine.process_transaction_ref(&deposit).unwrap();
+        // The caller still owns the transaction and can keep using it
+        assert_eq!(deposit.amount().unwrap(), dec("2.0"));
+        let mut other_engine = TransactionEngine::new();
+        other_engine.process_transaction(deposit).unwrap();
+        assert_eq!(other_engine.accounts.get(&1).unwrap().total, dec("2.0"));
+
+        // The stored copy of the deposit can be disputed
+        let dispute = Transaction::from(Dispute, 1, 1, Option::<&str>::None);
+        engine.process_transaction_ref(&dispute).unwrap();
+        let current_acct = engine.accounts.get(&1).unwrap();
+        assert_eq!(current_acct.held, dec("2.0"));
+        assert_eq!(current_acct.available, dec("0"));
+        // Dispute-family transactions are never stored
+        assert_eq!(engine.transactions.len(), 1);
+    }
+
+    #[test]
+    fn locked_accounts_displayed_as_zero() {
+        let mut engine = TransactionEngine::new().with_zeroed_locked_display(true);
+        let none = Option::<&str>::None;
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("5.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 2, Some("1.5")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, 1, 2, none))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Chargeback, 1, 2, none))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 2, 3, Some("2.0")))
+            .unwrap();
+        let mut output = Vec::new();
+        engine
+            .write_selected_accounts(&mut output, engine.retrieve_accounts_insertion_order())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+            1,0.0000,0.0000,0.0000,true\n\
+            2,2.0000,0.0000,2.0000,false\n"
+        );
+        // The real balances are untouched
+        let acct = engine.account(1).unwrap();
+        assert_eq!(acct.available(), dec("5.0"));
+        assert_eq!(acct.total(), dec("5.0"));
+        assert!(acct.locked());
+        assert!(engine.account(3).is_none());
+    }
+
+    #[test]
+    fn interest_accrues_on_held_funds() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("100.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 2, Some("50.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, 1, 1, Option::<&str>::None))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 2, 3, Some("20.0")))
+            .unwrap();
+        engine.accrue_held_interest(dec("0.01"));
+        engine.accrue_held_interest(dec("0.01"));
+        let current_acct = engine.accounts.get(&1).unwrap();
+        assert_eq!(current_acct.held, dec("102.01"));
+        assert_eq!(current_acct.available, dec("50.0"));
+        assert_eq!(current_acct.total, dec("152.01"));
+        // Accounts with nothing held earn nothing
+        assert_eq!(engine.accounts.get(&2).unwrap().total, dec("20.0"));
+    }
+
+    #[test]
+    fn duplicate_transactions_are_rejected() {
+        let mut engine = TransactionEngine::new()
+            .with_duplicate_filter(1_000, 0.001)
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("1.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Withdrawal, 1, 2, Some("0.5")))
+            .unwrap();
+        for tx in [
+            Transaction::from(Deposit, 1, 1, Some("1.0")),
+            Transaction::from(Deposit, 2, 2, Some("3.0")),
+        ] {
+            let tx_id = tx.tx_id;
+            let err = engine.process_transaction(tx).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<EngineError>(),
+                Some(&EngineError::DuplicateTransaction(tx_id))
+            );
+        }
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("0.5"));
+        assert!(!engine.accounts.contains_key(&2));
+        // Disputes legitimately reuse the Id of the transaction they reference
+        engine
+            .process_transaction(Transaction::from(Dispute, 1, 1, Option::<&str>::None))
+            .unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().held, dec("1.0"));
+
+        // Rejected transactions can be retried with the same Id
+        let mut engine = TransactionEngine::new()
+            .with_duplicate_filter(100, 0.01)
+            .unwrap()
+            .with_min_deposit(dec("1.0"));
+        engine.process_lines(&["deposit,1,1,0.5"]).unwrap_err();
+        engine
+            .process_lines(&[
+                "withdrawal,1,2,1.0",
+                "deposit,1,1,2.0",
+                "withdrawal,1,2,1.0",
+            ])
+            .unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("1.0"));
+        for rate in [0.0, 1.0, -0.5, f64::NAN] {
+            assert!(TransactionEngine::new()
+                .with_duplicate_filter(100, rate)
+                .is_err());
+        }
+        let mut engine = TransactionEngine::new()
+            .with_duplicate_filter(1_000, 0.001)
+            .unwrap();
+
+        // Well within capacity no new transaction should be mistaken for a duplicate
+        for tx_id in 3..500 {
+            engine
+                .process_transaction(Transaction::from(Deposit, 3, tx_id, Some("1.0")))
+                .unwrap();
+        }
+    }
+
+    #[cfg(feature = "demo")]
+    #[test]
+    fn throttled_processing() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1.0\n\
+            deposit,1,2,1.0\n\
+            withdrawal,1,3,0.5\n\
+            deposit,2,4,1.0\n";
+        let delay = Duration::from_millis(20);
+        let mut engine = TransactionEngine::new();
+        let start = Instant::now();
+        let rows = engine
+            .process_reader_throttled(input.as_bytes(), delay)
+            .unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(rows, 4);
+        assert!(elapsed >= delay * 4);
+        assert!(elapsed < delay * 4 + Duration::from_secs(2));
+        assert_eq!(engine.account(1).unwrap().total(), dec("1.5"));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn process_from_async_reader() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,2.0\n\
+            \n\
+            withdrawal,1,2,0.5\n\
+            deposit,2,,1.0\n\
+            dispute,1,1,";
+        let mut engine = TransactionEngine::new().with_error_policy(ErrorPolicy::Collect);
+        let rows = engine.process_async_reader(input.as_bytes()).await.unwrap();
+        assert_eq!(rows, 4);
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available(), dec("-0.5"));
+        assert_eq!(account.held(), dec("2.0"));
+        assert_eq!(engine.errors(), [(3, EngineError::MissingTxId)]);
+
+        // A dispute straight after its deposit must find it even with the input arriving
+        // asynchronously
+        let input = "type,client,tx,amount\ndeposit,3,4,1.0\ndispute,3,4,\n";
+        engine.process_async_reader(input.as_bytes()).await.unwrap();
+        assert_eq!(engine.account(3).unwrap().held(), dec("1.0"));
+
+        let err = engine
+            .process_async_reader("deposit,1,3,1.0\n".as_bytes())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::MissingHeader)
+        );
+    }
+
+    #[cfg(feature = "tcp")]
+    #[test]
+    fn serve_over_tcp() {
+        use std::net::{TcpListener, TcpStream};
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            // Failed lines are still answered with an error when the policy carries on past them
+            let mut engine = TransactionEngine::new().with_error_policy(ErrorPolicy::Skip);
+            engine.serve_tcp_listener(&listener).map(|_| engine)
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request = |line: &str, reply_lines: usize| -> String {
+            writeln!(stream, "{}", line).unwrap();
+            let mut reply = String::new();
+            for _ in 0..reply_lines {
+                reader.read_line(&mut reply).unwrap();
+            }
+            reply
+        };
+        assert_eq!(request("deposit,1,1,5.0", 1), "ok\n");
+        assert_eq!(request("withdrawal,1,2,1.5", 1), "ok\n");
+        assert_eq!(request("deposit,2,3,2.0", 1), "ok\n");
+        assert!(request("deposit,2,4,abc", 1).starts_with("error: "));
+        assert!(request("withdrawal,2,5,", 1).starts_with("error: "));
+        let accounts = request("accounts", 4);
+        let mut rows: Vec<&str> = accounts.lines().collect();
+        assert_eq!(rows.pop(), Some(""));
+        rows[1..].sort_unstable();
+        assert_eq!(
+            rows,
+            [
+                "client,available,held,total,locked",
+                "1,3.5000,0.0000,3.5000,false",
+                "2,2.0000,0.0000,2.0000,false",
+            ]
+        );
+        request("quit", 0);
+
+        let engine = server.join().unwrap().unwrap();
+        assert_eq!(engine.account(1).unwrap().total(), dec("3.5"));
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn export_accounts_as_arrow() {
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::{Decimal128Type, UInt16Type};
+
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("1.23456")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Deposit, 2, 2, Some("5.0")))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, 2, 2, Option::<&str>::None))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Chargeback, 2, 2, Option::<&str>::None))
+            .unwrap();
+        let batch = engine.export_arrow();
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.num_columns(), 5);
+        let clients = batch.column(0).as_primitive::<UInt16Type>();
+        let row = |client: u16| {
+            clients
+                .values()
+                .iter()
+                .position(|id| *id == client)
+                .unwrap()
+        };
+        let available = batch.column(1).as_primitive::<Decimal128Type>();
+        let total = batch.column(3).as_primitive::<Decimal128Type>();
+        let locked = batch.column(4).as_boolean();
+        assert_eq!(available.value_as_string(row(1)), "1.2345");
+        assert_eq!(total.value_as_string(row(1)), "1.2346");
+        assert_eq!(total.value_as_string(row(2)), "0.0000");
+        assert!(!locked.value(row(1)));
+        assert!(locked.value(row(2)));
+
+        // Accounts with no activity are left out or marked like in the CSV output
+        let lines = ["deposit,1,1,1.0", "dispute,2,7,"];
+        let mut engine = TransactionEngine::new().with_no_activity_output(NoActivityOutput::Omit);
+        engine.process_lines(&lines).unwrap();
+        assert_eq!(engine.export_arrow().num_rows(), 1);
+        let mut engine = TransactionEngine::new().with_no_activity_output(NoActivityOutput::Mark);
+        engine.process_lines(&lines).unwrap();
+        let batch = engine.export_arrow();
+        assert_eq!(batch.num_columns(), 6);
+        let no_activity = batch.column(5).as_boolean();
+        assert_eq!(no_activity.true_count(), 1);
+    }
+
+    #[test]
+    fn accounts_with_held_largest_first() {
+        let mut engine = TransactionEngine::new();
+        let none = Option::<&str>::None;
+        for (client_id, tx_id, amount) in
+            [(1, 1, "1.0"), (2, 2, "5.0"), (3, 3, "2.0"), (4, 4, "5.0")]
+        {
+            engine
+                .process_transaction(Transaction::from(Deposit, client_id, tx_id, Some(amount)))
+                .unwrap();
+        }
+        for tx_id in [1, 2, 3, 4] {
+            engine
+                .process_transaction(Transaction::from(Dispute, tx_id as u16, tx_id, none))
+                .unwrap();
+        }
+        engine
+            .process_transaction(Transaction::from(Resolve, 1, 1, none))
+            .unwrap();
+        let held: Vec<(u16, Decimal)> = engine
+            .accounts_with_held()
+            .iter()
+            .map(|acct| (acct.id(), acct.held()))
+            .collect();
+        assert_eq!(
+            held,
+            vec![(2, dec("5.0")), (4, dec("5.0")), (3, dec("2.0"))]
+        );
+    }
+
+    #[test]
+    fn transaction_type_aliases() {
+        let mut engine = TransactionEngine::new()
+            .with_type_alias("credit", Deposit)
+            .with_type_alias("debit", Withdrawal);
+        let input = "client,tx,type,amount\n\
+            1,1,credit,5.0\n\
+            1,2,debit,2.0\n\
+            1,3,deposit,1.0\n\
+            1,2,dispute,\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+        // The aliased debit was stored as a withdrawal so disputing it puts the funds back
+        let current_acct = engine.accounts.get(&1).unwrap();
+        assert_eq!(current_acct.available, dec("4.0"));
+        assert_eq!(current_acct.held, dec("2.0"));
+        assert_eq!(current_acct.total, dec("6.0"));
+        // Without the aliases the same input is rejected
+        let mut engine = TransactionEngine::new();
+        assert!(engine.process_reader(input.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn what_if_leaves_original_untouched() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("10.0")))
+            .unwrap();
+        let accounts = engine
+            .what_if(&[
+                Transaction::from(Withdrawal, 1, 2, Some("4.0")),
+                Transaction::from(Dispute, 1, 1, Option::<&str>::None),
+                Transaction::from(Deposit, 2, 3, Some("1.0")),
+            ])
+            .unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].available(), dec("-4.0"));
+        assert_eq!(accounts[0].held(), dec("10.0"));
+        assert_eq!(accounts[1].total(), dec("1.0"));
+
+        let current_acct = engine.accounts.get(&1).unwrap();
+        assert_eq!(current_acct.available, dec("10.0"));
+        assert_eq!(current_acct.held, dec("0"));
+        assert!(!engine.accounts.contains_key(&2));
+        assert!(engine.disputed_transactions.is_empty());
+        assert_eq!(engine.transactions.len(), 1);
+    }
+
+    #[test]
+    fn explain_rejected_withdrawal() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("1.0")))
+            .unwrap();
+        let explanation = engine.explain(&Transaction::from(Withdrawal, 1, 2, Some("2.5")));
+        assert!(explanation.contains("Processing withdrawal 2 for client 1"));
+        assert!(explanation.contains("insufficient funds to withdraw 2.5 with 1.0 available"));
+        // Explaining doesn't touch the engine
+        assert_eq!(engine.accounts.get(&1).unwrap().available, dec("1.0"));
+        assert!(engine.trace.is_none());
+
+        let explanation = engine.explain(&Transaction::from(Dispute, 1, 1, Option::<&str>::None));
+        assert!(explanation.contains("Moved the disputed deposit's 1.0 from available to held"));
+        assert!(explanation.contains("Account after: available 0.0, held 1.0, total 1.0"));
+        assert!(engine.disputed_transactions.is_empty());
+        let explanation = engine.explain(&Transaction::from(Resolve, 1, 1, Option::<&str>::None));
+        assert!(explanation.contains("Ignored: transaction 1 is not disputed"));
+        let explanation = engine.explain(&Transaction::from(Deposit, 1, 3, Some("bad")));
+        assert!(explanation.contains("Failed: Failed to get deposit amount"));
+    }
+
+    #[test]
+    fn accounts_equal_below_scale() {
+        let account = |available: &str, held: &str| AccountWithId {
+            id: 1,
+            account: Account {
+                available: dec(available),
+                held: dec(held),
+                total: dec(available) + dec(held),
+                locked: false,
+            },
+        };
+        let a = account("1.2345", "0.5");
+        let b = account("1.23449", "0.50001");
+        assert!(accounts_equal_at_scale(&a, &b, 4));
+        assert!(!accounts_equal_at_scale(&a, &b, 5));
+        assert!(!accounts_equal_at_scale(&a, &account("1.2346", "0.5"), 4));
+    }
+
+    #[test]
+    fn system_wide_held_over_ratio_is_flagged() {
+        let mut engine = TransactionEngine::new().with_max_system_held_ratio(dec("0.25"));
+        let none = Option::<&str>::None;
+        let deposits = [
+            (1, 1, "1.5"),
+            (1, 2, "2.5"),
+            (2, 3, "1.0"),
+            (2, 4, "3.0"),
+            (3, 5, "1.0"),
+            (3, 6, "1.0"),
+        ];
+        for (client, tx, amount) in deposits {
+            engine
+                .process_transaction(Transaction::from(Deposit, client, tx, Some(amount)))
+                .unwrap();
+        }
+        // A quarter of the system's 10.0 total held is not over the fraction
+        engine
+            .process_transaction(Transaction::from(Dispute, 1, 1, none))
+            .unwrap();
+        engine
+            .process_transaction(Transaction::from(Dispute, 2, 3, none))
+            .unwrap();
+        assert!(!engine.systemic_dispute_flagged());
+        engine
+            .process_transaction(Transaction::from(Dispute, 3, 5, none))
+            .unwrap();
+        assert!(engine.systemic_dispute_flagged());
+        engine
+            .process_transaction(Transaction::from(Resolve, 3, 5, none))
+            .unwrap();
+        assert!(engine.systemic_dispute_flagged());
+    }
+
+    #[test]
+    fn process_raw_lines() {
+        let mut engine = TransactionEngine::new();
+        let rows = engine
+            .process_lines(&[
+                "deposit,1,1,2.0",
+                "withdrawal,1,2,0.5",
+                "deposit,2,3,1.0",
+                "dispute,2,3,",
+            ])
+            .unwrap();
+        assert_eq!(rows, 4);
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available(), dec("1.5"));
+        let account = engine.account(2).unwrap();
+        assert_eq!(account.held(), dec("1.0"));
+        assert_eq!(engine.process_lines(&[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn max_total_balance_rejects_excess_deposits() {
+        let mut engine = TransactionEngine::new().with_max_total_balance(dec("100.0"));
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("60.0")))
+            .unwrap();
+        let err = engine
+            .process_transaction(Transaction::from(Deposit, 1, 2, Some("40.0001")))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::AboveMaximumBalance {
+                amount: dec("40.0001"),
+                maximum: dec("100.0"),
+            })
+        );
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("60.0"));
+        assert!(!engine.transactions.contains_key(&2));
+        // Reaching the cap exactly is allowed
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 3, Some("40.0")))
+            .unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("100.0"));
+    }
+
+    #[test]
+    fn production_mode_ignores_sandbox_transactions() {
+        let mut engine = TransactionEngine::new().with_data_mode(DataMode::Production);
+        let input = "type,client,tx,amount,sandbox\n\
+            deposit,1,1,5.0,false\n\
+            deposit,1,2,1.0,true\n\
+            deposit,2,3,2.0,true\n\
+            deposit,1,4,0.5,\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("5.5"));
+        assert!(!engine.accounts.contains_key(&2));
+        // Input without the column at all is production data
+        let mut engine = TransactionEngine::new().with_data_mode(DataMode::Production);
+        engine.process_lines(&["deposit,1,1,5.0"]).unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("5.0"));
+    }
+
+    #[test]
+    fn sandbox_mode_only_processes_sandbox_transactions() {
+        let mut engine = TransactionEngine::new().with_data_mode(DataMode::Sandbox);
+        let input = "type,client,tx,amount,sandbox\n\
+            deposit,1,1,5.0,false\n\
+            deposit,1,2,1.0,true\n\
+            deposit,2,3,2.0,\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("1.0"));
+        assert!(!engine.accounts.contains_key(&2));
+        engine
+            .process_transaction(Transaction::from(Dispute, 1, 2, Option::<&str>::None))
+            .unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().held, dec("0"));
+        engine
+            .process_transaction(
+                Transaction::from(Dispute, 1, 2, Option::<&str>::None).with_sandbox(true),
+            )
+            .unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().held, dec("1.0"));
+    }
+
+    #[test]
+    fn withdrawable_includes_overdraft() {
+        let mut engine = TransactionEngine::new();
+        engine.process_lines(&["deposit,1,1,5.0"]).unwrap();
+        assert_eq!(engine.withdrawable(1), Some(dec("5.0")));
+        assert_eq!(engine.withdrawable(2), None);
+
+        let mut engine = TransactionEngine::new().with_overdraft_limit(dec("2.5"));
+        engine.process_lines(&["deposit,1,1,5.0"]).unwrap();
+        assert_eq!(engine.withdrawable(1), Some(dec("7.5")));
+        engine.process_lines(&["withdrawal,1,2,7.5"]).unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().available, dec("-2.5"));
+        assert_eq!(engine.withdrawable(1), Some(dec("0")));
+        // Nothing more can be withdrawn once the overdraft is used up
+        engine.process_lines(&["withdrawal,1,3,0.0001"]).unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("-2.5"));
+    }
+
+    #[test]
+    fn effective_available_excludes_holds_and_overdraft() {
+        let mut engine = TransactionEngine::new()
+            .with_chargeback_lock_threshold(2)
+            .with_overdraft_limit(dec("1.0"));
+        engine
+            .process_lines(&["deposit,1,1,5.0", "deposit,1,2,3.0", "dispute,1,2,"])
+            .unwrap();
+        assert_eq!(engine.effective_available(1), Some(dec("5.0")));
+        assert_eq!(engine.withdrawable(1), Some(dec("6.0")));
+        assert_eq!(engine.effective_available(2), None);
+        // Within the grace before locking the rest of the account can still be spent
+        engine.process_lines(&["chargeback,1,2,"]).unwrap();
+        assert!(!engine.account(1).unwrap().locked());
+        assert_eq!(engine.effective_available(1), Some(dec("5.0")));
+        engine
+            .process_lines(&["withdrawal,1,3,4.0", "dispute,1,1,"])
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().available(), dec("-4.0"));
+        assert_eq!(engine.effective_available(1), Some(dec("0")));
+        engine.process_lines(&["resolve,1,1,"]).unwrap();
+        assert_eq!(engine.effective_available(1), Some(dec("1.0")));
+        engine
+            .process_lines(&["dispute,1,1,", "chargeback,1,1,"])
+            .unwrap();
+        assert!(engine.account(1).unwrap().locked());
+        assert_eq!(engine.effective_available(1), Some(dec("0")));
+    }
+
+    #[test]
+    fn repeated_chargebacks_escalate_to_lock() {
+        let mut engine = TransactionEngine::new().with_chargeback_lock_threshold(3);
+        engine
+            .process_lines(&[
+                "deposit,1,1,1.0",
+                "deposit,1,2,2.0",
+                "deposit,1,3,3.0",
+                "deposit,1,4,4.0",
+                "dispute,1,1,",
+                "chargeback,1,1,",
+                "dispute,1,2,",
+                "chargeback,1,2,",
+            ])
+            .unwrap();
+        assert_eq!(engine.chargeback_count(1), 2);
+        assert!(!engine.accounts.get(&1).unwrap().locked);
+        engine
+            .process_lines(&["dispute,1,3,", "chargeback,1,3,"])
+            .unwrap();
+        assert_eq!(engine.chargeback_count(1), 3);
+        let account = engine.accounts.get(&1).unwrap();
+        assert!(account.locked);
+        assert_eq!(account.total, dec("4.0"));
+        // Without the threshold the first chargeback locks
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&["deposit,1,1,1.0", "dispute,1,1,", "chargeback,1,1,"])
+            .unwrap();
+        assert!(engine.accounts.get(&1).unwrap().locked);
+    }
+
+    #[test]
+    fn process_sorted_by_tx_id() {
+        let txs = vec![
+            Transaction::from(Withdrawal, 1, 3, Some("4.0")),
+            Transaction::from(Deposit, 1, 2, Some("3.0")),
+            Transaction::from(Deposit, 1, 1, Some("2.0")),
+        ];
+        // In the given order the withdrawal comes first and is rejected
+        let mut engine = TransactionEngine::new();
+        engine.process_sorted_by(txs.clone(), |_| 0).unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("5.0"));
+        // Sorted by Id the deposits come first and cover it
+        let mut engine = TransactionEngine::new();
+        engine.process_sorted_by(txs, Transaction::tx_id).unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("1.0"));
+    }
+
+    #[test]
+    fn balance_events_sent_to_channel() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let consumer = std::thread::spawn(move || receiver.iter().collect::<Vec<BalanceEvent>>());
+        let mut engine = TransactionEngine::new().with_event_channel(sender);
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "withdrawal,1,2,9.0",
+                "deposit,2,3,1.0",
+                "dispute,1,1,",
+                "chargeback,1,1,",
+            ])
+            .unwrap();
+        // Forks don't send events
+        engine
+            .what_if(&[Transaction::from(Deposit, 2, 4, Some("1.0"))])
+            .unwrap();
+        drop(engine);
+
+        let events = consumer.join().unwrap();
+        let summary: Vec<(TxId, TransactionType, u16, Decimal, Decimal, bool)> = events
+            .iter()
+            .map(|event| {
+                (
+                    event.tx_id,
+                    event.tx_type,
+                    event.account.id(),
+                    event.account.available(),
+                    event.account.held(),
+                    event.account.locked(),
+                )
+            })
+            .collect();
+        // The rejected withdrawal changed nothing so sent no event
+        assert_eq!(
+            summary,
+            vec![
+                (1, Deposit, 1, dec("5.0"), dec("0"), false),
+                (3, Deposit, 2, dec("1.0"), dec("0"), false),
+                (1, Dispute, 1, dec("0"), dec("5.0"), false),
+                (1, Chargeback, 1, dec("0"), dec("0"), true),
+            ]
+        );
+    }
+
+    #[cfg(feature = "wide-tx-id")]
+    #[test]
+    fn tx_ids_above_u32_range() {
+        let mut engine = TransactionEngine::new()
+            .with_duplicate_filter(100, 0.01)
+            .unwrap();
+        let tx_id = u32::MAX as TxId + 1;
+        let input = format!(
+            "type,client,tx,amount\ndeposit,1,{},5.0\ndeposit,1,1,1.0\ndispute,1,{},\n",
+            tx_id, tx_id
+        );
+        engine.process_reader(input.as_bytes()).unwrap();
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.held, dec("5.0"));
+        assert_eq!(account.available, dec("1.0"));
+        let err = engine
+            .process_transaction(Transaction::from(Deposit, 1, tx_id, Some("1.0")))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::DuplicateTransaction(tx_id))
+        );
+    }
+
+    #[test]
+    fn verify_input_against_expected_accounts() {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5.0\n\
+            deposit,2,2,1.23456\n\
+            withdrawal,1,3,1.5\n";
+        let expected = "client,available,held,total,locked\n\
+            1,3.5000,0.0000,3.5000,false\n\
+            2,1.2345,0.0000,1.2346,false\n";
+        assert_eq!(
+            TransactionEngine::verify_against_expected(input.as_bytes(), expected.as_bytes())
+                .unwrap(),
+            Ok(())
+        );
+
+        let expected = "client,available,held,total,locked\n\
+            1,3.0000,0.0000,3.0000,false\n\
+            3,1.0000,0.0000,1.0000,true\n";
+        let diffs =
+            TransactionEngine::verify_against_expected(input.as_bytes(), expected.as_bytes())
+                .unwrap()
+                .unwrap_err();
+        assert_eq!(
+            diffs,
+            vec![
+                AccountDiff {
+                    id: 1,
+                    available: dec("0.5"),
+                    held: dec("0"),
+                    total: dec("0.5"),
+                    locked: None,
+                },
+                AccountDiff {
+                    id: 2,
+                    available: dec("1.2345"),
+                    held: dec("0"),
+                    total: dec("1.2346"),
+                    locked: None,
+                },
+                AccountDiff {
+                    id: 3,
+                    available: dec("-1.0"),
+                    held: dec("0"),
+                    total: dec("-1.0"),
+                    locked: Some(false),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn restored_locked_account_rejects_transactions() {
+        let accounts = "client,available,held,total,locked\n\
+            1,5.0000,0.0000,5.0000,true\n\
+            2,1.0000,0.0000,1.0000,false\n";
+        let mut engine = TransactionEngine::from_accounts_csv(accounts.as_bytes()).unwrap();
+        engine
+            .process_lines(&["deposit,1,1,2.0", "withdrawal,1,2,1.0", "deposit,2,3,2.0"])
+            .unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("5.0"));
+        assert_eq!(engine.accounts.get(&2).unwrap().total, dec("3.0"));
+
+        engine
+            .process_lines(&[
+                "unlock,1,4,",
+                "deposit,1,5,2.0",
+                "lock,2,6,",
+                "deposit,2,7,1.0",
+            ])
+            .unwrap();
+        let account = engine.accounts.get(&1).unwrap();
+        assert!(!account.locked);
+        assert_eq!(account.total, dec("7.0"));
+        let account = engine.accounts.get(&2).unwrap();
+        assert!(account.locked);
+        assert_eq!(account.total, dec("3.0"));
+
+        // An account locked by a chargeback can't be unlocked from the input
+        engine
+            .process_lines(&[
+                "deposit,3,8,4.0",
+                "dispute,3,8,",
+                "chargeback,3,8,",
+                "unlock,3,9,",
+                "deposit,3,10,1.0",
+            ])
+            .unwrap();
+        let account = engine.accounts.get(&3).unwrap();
+        assert!(account.locked);
+        assert_eq!(account.total, dec("0"));
+    }
+
+    #[test]
+    fn process_full_returns_outcomes_and_accounts() {
+        let none = Option::<&str>::None;
+        let mut engine = TransactionEngine::new().with_min_deposit(dec("1.0"));
+        let (outcomes, accounts) = engine.process_full(vec![
+            Transaction::from(Deposit, 2, 1, Some("5.0")),
+            Transaction::from(Deposit, 1, 2, Some("1.0")),
+            Transaction::from(Withdrawal, 1, 3, Some("2.0")),
+            Transaction::from(Deposit, 1, 4, Some("0.5")),
+            Transaction::from(Dispute, 2, 9, none),
+            Transaction::from(Resolve, 2, 1, none),
+            Transaction::from(Dispute, 2, 1, none),
+            Transaction::from(Chargeback, 2, 1, none),
+            Transaction::from(Deposit, 2, 5, Some("1.0")),
+        ]);
+        assert_eq!(
+            outcomes,
+            vec![
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::InsufficientFunds,
+                ProcessOutcome::Failed(EngineError::BelowMinimumDeposit {
+                    amount: dec("0.5"),
+                    minimum: dec("1.0"),
+                }),
+                ProcessOutcome::UnknownTransaction,
+                ProcessOutcome::ResolveWithoutDispute,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::AccountLocked,
+            ]
+        );
+        let accounts: Vec<(u16, Decimal, bool)> = accounts
+            .iter()
+            .map(|acct| (acct.id(), acct.total(), acct.locked()))
+            .collect();
+        assert_eq!(accounts, vec![(1, dec("1.0"), false), (2, dec("0"), true)]);
+    }
+
+    #[test]
+    fn zero_deposits_rejected() {
+        let mut engine = TransactionEngine::new();
+        let err = engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("0.0000")))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::ZeroDeposit)
+        );
+        assert!(!engine.transactions.contains_key(&1));
+
+        let mut engine = TransactionEngine::new().with_zero_deposits_allowed(true);
+        engine
+            .process_transaction(Transaction::from(Deposit, 1, 1, Some("0")))
+            .unwrap();
+        assert!(engine.transactions.contains_key(&1));
+    }
+
+    #[test]
+    fn disputes_without_amounts_processed() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&[
+                "deposit,1,1,2.0",
+                "dispute,1,1,",
+                "resolve,1,1,0",
+                "dispute,1,1,0.0",
+            ])
+            .unwrap();
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.held, dec("2.0"));
+        assert_eq!(account.available, dec("0"));
+    }
+
+    #[test]
+    fn recover_from_snapshot_and_wal() {
+        let none = Option::<&str>::None;
+        let txs = [
+            Transaction::from(Deposit, 1, 1, Some("5.0")),
+            Transaction::from(Deposit, 2, 2, Some("3.0")),
+            Transaction::from(Dispute, 1, 1, none),
+            Transaction::from(Withdrawal, 2, 3, Some("1.0")),
+            Transaction::from(Deposit, 2, 4, Some("0")),
+            Transaction::from(Resolve, 1, 1, none),
+            Transaction::from(Dispute, 2, 2, none),
+            Transaction::from(Chargeback, 2, 2, none),
+        ];
+        let mut wal = Vec::new();
+        let mut engine = TransactionEngine::new();
+        for tx in txs[..3].iter().cloned() {
+            engine.process_transaction_logged(tx, &mut wal).unwrap();
+        }
+        let snapshot = engine.snapshot();
+        assert_eq!(snapshot.sequence(), 3);
+        for tx in txs[3..].iter().cloned() {
+            // The zero deposit fails but is still logged
+            let _ = engine.process_transaction_logged(tx, &mut wal);
+        }
+        let mut expected: Vec<(u16, Account)> = engine
+            .retrieve_accounts()
+            .map(|acct| (acct.id, acct.account))
+            .collect();
+        // Crash part way through writing the next entry
+        wal.extend_from_slice(b"{\"seq\":9,\"type\":\"depo");
+        drop(engine);
+
+        // The snapshot survives a round trip through JSON
+        let snapshot: EngineSnapshot =
+            serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+        let recovered = TransactionEngine::new()
+            .recover(snapshot, wal.as_slice())
+            .unwrap();
+        let mut recovered_accounts: Vec<(u16, Account)> = recovered
+            .retrieve_accounts()
+            .map(|acct| (acct.id, acct.account))
+            .collect();
+        recovered_accounts.sort_by_key(|(id, _)| *id);
+        expected.sort_by_key(|(id, _)| *id);
+        assert_eq!(recovered_accounts, expected);
+        assert!(recovered.accounts.get(&2).unwrap().locked);
+        assert_eq!(recovered.sequence, 8);
+        assert!(recovered.disputed_transactions.is_empty());
+    }
+
+    #[test]
+    fn snapshot_formats_round_trip() {
+        let mut engine = TransactionEngine::new()
+            .with_duplicate_filter(100, 0.01)
+            .unwrap();
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.1234",
+                "deposit,2,2,3.0",
+                "withdrawal,2,3,1.5",
+                "dispute,1,1,",
+                "dispute,2,2,",
+                "chargeback,2,2,",
+            ])
+            .unwrap();
+        // Optional transaction fields have to survive formats that aren't self-describing
+        engine
+            .process_transaction(
+                Transaction::new(Deposit, 3, 4, Some(dec("2.0")))
+                    .with_sandbox(false)
+                    .with_idempotency_key("key-4"),
+            )
+            .unwrap();
+        let mut sizes = Vec::new();
+        for format in &[SnapshotFormat::Json, SnapshotFormat::Bincode] {
+            let mut written = Vec::new();
+            engine.snapshot_to(&mut written, *format).unwrap();
+            sizes.push(written.len());
+            let snapshot = EngineSnapshot::read_from(written.as_slice(), *format).unwrap();
+            assert_eq!(snapshot.sequence(), 7);
+            let mut restored = TransactionEngine::new()
+                .recover(snapshot, io::empty())
+                .unwrap();
+            assert_eq!(restored.debug_state(), engine.debug_state());
+            assert_eq!(restored.account_order, engine.account_order);
+            assert_eq!(restored.lock_report().len(), 1);
+            // The restored duplicate filter still knows the transactions already seen
+            let err = restored.process_lines(&["deposit,3,1,1.0"]).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<EngineError>(),
+                Some(&EngineError::DuplicateTransaction(1))
+            );
+        }
+        assert!(sizes[1] < sizes[0]);
+        assert!(EngineSnapshot::read_from(b"{".as_ref(), SnapshotFormat::Bincode).is_err());
+    }
+
+    #[test]
+    fn sync_replica_with_delta() {
+        let mut primary = TransactionEngine::new();
+        primary
+            .process_lines(&["deposit,1,1,5.0", "deposit,2,2,3.0", "deposit,3,3,1.0"])
+            .unwrap();
+        let snapshot = primary.snapshot();
+        let mut replica = TransactionEngine::new()
+            .recover(snapshot.clone(), io::empty())
+            .unwrap();
+        primary
+            .process_lines(&[
+                "withdrawal,1,4,1.5",
+                "dispute,2,2,",
+                "chargeback,2,2,",
+                "deposit,4,5,2.0",
+                "dispute,4,5,",
+            ])
+            .unwrap();
+
+        let delta = primary.delta_since(&snapshot);
+        let ids: Vec<u16> = delta.iter().map(|diff| diff.id).collect();
+        assert_eq!(ids, [1, 2, 4]);
+        replica.apply_diffs(&delta);
+        assert_eq!(
+            replica.debug_state().accounts,
+            primary.debug_state().accounts
+        );
+        assert_eq!(replica.account_order, primary.account_order);
+        assert_eq!(replica.delta_since(&snapshot), delta);
+    }
+
+    #[test]
+    fn clients_off_allowlist_rejected() {
+        let path = std::env::temp_dir().join(format!("allowlist-{}.txt", std::process::id()));
+        std::fs::write(&path, "# Fixed roster\n1\n\n 3 \n").unwrap();
+        let allowlist = std::fs::File::open(&path).unwrap();
+        let mut engine = TransactionEngine::new()
+            .with_client_allowlist(allowlist)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        engine
+            .process_lines(&["deposit,1,1,1.0", "deposit,3,2,1.0"])
+            .unwrap();
+        let err = engine.process_lines(&["deposit,2,3,1.0"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::ClientNotAllowed(2))
+        );
+        assert!(!engine.accounts.contains_key(&2));
+        assert_eq!(engine.accounts.len(), 2);
+
+        let err = TransactionEngine::new()
+            .with_client_allowlist("1\nclient-2\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "Invalid client Id on line 2 of allowlist"
+        );
+    }
+
+    #[test]
+    fn debug_state_reflects_processing() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,2,2,1.5",
+                "withdrawal,1,3,2.0",
+                "dispute,2,2,",
+            ])
+            .unwrap();
+        let state = engine.debug_state();
+        assert_eq!(
+            state.accounts.get(&1),
+            Some(&DebugAccount {
+                available: dec("3.0"),
+                held: dec("0"),
+                total: dec("3.0"),
+                locked: false,
+            })
+        );
+        assert_eq!(state.accounts.get(&2).unwrap().held, dec("1.5"));
+        assert_eq!(
+            state.transactions.keys().copied().collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            state.transactions.get(&3),
+            Some(&DebugTransaction {
+                tx_type: Withdrawal,
+                client: 1,
+                amount: Some("2.0".to_string()),
+            })
+        );
+        assert_eq!(state.disputed.iter().copied().collect::<Vec<_>>(), vec![2]);
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(json.contains("\"disputed\":[2]"));
+    }
+
+    #[test]
+    fn resolve_before_dispute_outcome() {
+        let none = Option::<&str>::None;
+        let mut engine = TransactionEngine::new();
+        let (outcomes, _) = engine.process_full(vec![
+            Transaction::from(Deposit, 1, 1, Some("5.0")),
+            Transaction::from(Resolve, 1, 1, none),
+            Transaction::from(Chargeback, 1, 1, none),
+            Transaction::from(Dispute, 1, 1, none),
+            Transaction::from(Resolve, 1, 1, none),
+        ]);
+        assert_eq!(
+            outcomes,
+            vec![
+                ProcessOutcome::Applied,
+                ProcessOutcome::ResolveWithoutDispute,
+                ProcessOutcome::ResolveWithoutDispute,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+            ]
+        );
+        assert_eq!(engine.resolves_without_dispute(), 2);
+        assert!(!engine.accounts.get(&1).unwrap().locked);
+
+        let mut engine = TransactionEngine::new().with_strict_settlement(true);
+        engine.process_lines(&["deposit,1,1,5.0"]).unwrap();
+        let err = engine.process_lines(&["resolve,1,1,"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::ResolveWithoutDispute(1))
+        );
+        assert_eq!(engine.resolves_without_dispute(), 1);
+    }
+
+    #[test]
+    fn balance_partway_through_history() {
+        let mut engine = TransactionEngine::new().with_history(true);
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,2,2,7.0",
+                "withdrawal,1,3,2.0",
+                "withdrawal,1,4,9.0",
+                "dispute,1,1,",
+                "deposit,1,5,1.0",
+            ])
+            .unwrap();
+        let account = engine.balance_at_tx(1, 3).unwrap();
+        assert_eq!(account.available(), dec("3.0"));
+        assert_eq!(account.total(), dec("3.0"));
+        // The rejected withdrawal left the balance as it was
+        assert_eq!(engine.balance_at_tx(1, 4).unwrap().total(), dec("3.0"));
+        let account = engine.balance_at_tx(1, 5).unwrap();
+        assert_eq!(account.available(), dec("-1.0"));
+        assert_eq!(account.held(), dec("5.0"));
+        assert!(engine.balance_at_tx(1, 2).is_none());
+        assert!(TransactionEngine::new().balance_at_tx(1, 1).is_none());
+
+        // Remapped client Ids aren't remapped a second time on replay
+        let client_id_map = vec![(10, 1), (1, 2)].into_iter().collect();
+        let mut engine = TransactionEngine::new()
+            .with_history(true)
+            .with_client_id_map(client_id_map)
+            .with_control_account(999);
+        engine
+            .process_lines(&["deposit,10,1,5.0", "deposit,1,2,3.0", "withdrawal,10,3,1.0"])
+            .unwrap();
+        assert_eq!(engine.balance_at_tx(1, 3).unwrap().total(), dec("4.0"));
+        assert_eq!(engine.balance_at_tx(2, 2).unwrap().total(), dec("3.0"));
+    }
+
+    #[test]
+    fn display_mismatch_warning() {
+        let mut engine = TransactionEngine::new().with_display_mismatch_warnings(true);
+        engine
+            .process_lines(&["deposit,1,1,1.00006", "deposit,2,2,1.0"])
+            .unwrap();
+        let mut output = Vec::new();
+        let mut warnings = Vec::new();
+        engine
+            .write_selected_accounts_with_warnings(
+                &mut output,
+                engine.retrieve_accounts_insertion_order(),
+                &mut warnings,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+            1,1.0000,0.0000,1.0001,false\n\
+            2,1.0000,0.0000,1.0000,false\n"
+        );
+        assert_eq!(
+            String::from_utf8(warnings).unwrap(),
+            "Warning: client 1 is displayed with available 1.0000 and held 0.0000 which don't add \
+             up to total 1.0001\n"
+        );
+        // Without the option nothing is warned about
+        let engine = TransactionEngine {
+            config: EngineConfig::default(),
+            ..engine
+        };
+        let mut warnings = Vec::new();
+        engine
+            .write_accounts_with_warnings(io::sink(), &mut warnings)
+            .unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn reconciled_total_display() {
+        let lines = ["deposit,1,1,1.00005", "deposit,1,2,1.00005", "dispute,1,1,"];
+        let mut engine = TransactionEngine::new();
+        engine.process_lines(&lines).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts(&mut output).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("1,1.0000,1.0000,2.0001,false\n"));
+
+        let mut engine = TransactionEngine::new().with_reconciled_total_display(true);
+        engine.process_lines(&lines).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts(&mut output).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("1,1.0000,1.0000,2.0000,false\n"));
+        assert!(engine
+            .reconcile(Decimal::ZERO)
+            .display_mismatches
+            .is_empty());
+        // The real total is untouched
+        assert_eq!(engine.account(1).unwrap().total(), dec("2.0001"));
+    }
+
+    #[test]
+    fn repeated_idempotency_key_dropped() {
+        let mut engine = TransactionEngine::new().with_idempotency_keys(true);
+        let input = "type,client,tx,amount,idempotency_key\n\
+            deposit,1,1,5.0,6f1c0e52-4f0b-4a8e-9f38-d2b1f4a3c001\n\
+            deposit,1,2,5.0,6f1c0e52-4f0b-4a8e-9f38-d2b1f4a3c001\n\
+            deposit,1,3,1.0,\n\
+            deposit,1,4,1.0,\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("7.0"));
+        assert!(!engine.transactions.contains_key(&2));
+
+        let (outcomes, _) = engine.process_full(vec![
+            Transaction::from(Withdrawal, 1, 5, Some("1.0")).with_idempotency_key("a"),
+            Transaction::from(Withdrawal, 1, 6, Some("1.0")).with_idempotency_key("a"),
+        ]);
+        assert_eq!(
+            outcomes,
+            vec![
+                ProcessOutcome::Applied,
+                ProcessOutcome::DuplicateIdempotencyKey
+            ]
+        );
+
+        // A transaction that wasn't applied doesn't use up its key
+        let (outcomes, _) = engine.process_full(vec![
+            Transaction::from(Withdrawal, 1, 7, Some("50.0")).with_idempotency_key("b"),
+            Transaction::from(Deposit, 1, 8, Some("50.0")),
+            Transaction::from(Withdrawal, 1, 7, Some("50.0")).with_idempotency_key("b"),
+        ]);
+        assert_eq!(
+            outcomes,
+            vec![
+                ProcessOutcome::InsufficientFunds,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied
+            ]
+        );
+        assert_eq!(engine.accounts.get(&1).unwrap().total, dec("6.0"));
+    }
+
+    #[test]
+    fn dust_deposits_rejected() {
+        let mut engine = TransactionEngine::new().with_min_meaningful_amount(dec("0.01"));
+        let err = engine.process_lines(&["deposit,1,1,0.0099"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::DustDeposit {
+                amount: dec("0.0099"),
+                threshold: dec("0.01"),
+            })
+        );
+        assert!(engine.transactions.is_empty());
+        engine.process_lines(&["deposit,1,2,0.01"]).unwrap();
+        assert_eq!(engine.transactions.len(), 1);
+
+        // The dust threshold and minimum deposit are kept apart, whichever order they're set in
+        let mut engine = TransactionEngine::new()
+            .with_min_deposit(dec("1.0"))
+            .with_min_meaningful_amount(dec("0.01"));
+        let err = engine.process_lines(&["deposit,1,1,0.5"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::BelowMinimumDeposit {
+                amount: dec("0.5"),
+                minimum: dec("1.0"),
+            })
+        );
+        let err = engine.process_lines(&["deposit,1,1,0.001"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>().map(EngineError::kind),
+            Some("DustDeposit")
+        );
+    }
+
+    #[test]
+    fn write_accounts_with_custom_records() {
+        #[derive(Serialize)]
+        struct HeldShare {
+            id: u16,
+            total: Decimal,
+            held_share: Decimal,
+        }
+
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&["deposit,1,1,2.0", "deposit,1,2,6.0", "dispute,1,1,"])
+            .unwrap();
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        engine
+            .write_accounts_with(&mut writer, |acct| HeldShare {
+                id: acct.id(),
+                total: acct.total(),
+                held_share: acct.held() / acct.total(),
+            })
+            .unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(output, "id,total,held_share\n1,8.0,0.25\n");
+    }
+
+    #[test]
+    fn zero_client_id_rejected() {
+        let mut engine = TransactionEngine::new().with_reject_zero_client_id(true);
+        let err = engine.process_lines(&["deposit,0,1,1.0"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::ClientNotAllowed(0))
+        );
+        assert!(engine.accounts.is_empty());
+        engine.process_lines(&["deposit,1,2,1.0"]).unwrap();
+
+        let mut engine = TransactionEngine::new();
+        engine.process_lines(&["deposit,0,1,1.0"]).unwrap();
+        assert_eq!(engine.accounts.get(&0).unwrap().total, dec("1.0"));
+    }
+
+    #[test]
+    fn dispute_of_spent_deposit() {
+        let lines = ["deposit,1,1,10.0", "withdrawal,1,2,8.0", "dispute,1,1,"];
+        let mut engine = TransactionEngine::new();
+        engine.process_lines(&lines).unwrap();
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.available, dec("-8.0"));
+        assert_eq!(account.held, dec("10.0"));
+        assert_eq!(account.total, dec("2.0"));
+        assert_eq!(engine.overdrawn_disputes(), vec![1]);
+
+        let mut engine = TransactionEngine::new().with_reject_overdrawn_disputes(true);
+        let err = engine.process_lines(&lines).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::DisputeExceedsAvailable(1))
+        );
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.available, dec("2.0"));
+        assert_eq!(account.held, dec("0"));
+        assert!(engine.disputed_transactions.is_empty());
+        assert!(engine.overdrawn_disputes().is_empty());
+    }
+
+    #[test]
+    fn write_selected_columns() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&["deposit,1,1,2.5", "dispute,1,1,"])
+            .unwrap();
+        let mut output = Vec::new();
+        engine
+            .write_accounts_columns(&mut output, &[AccountColumn::Client, AccountColumn::Total])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,total\n1,2.5000\n"
+        );
+        let mut output = Vec::new();
+        engine
+            .write_accounts_columns(&mut output, &[AccountColumn::Held, AccountColumn::Client])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "held,client\n2.5000,1\n"
+        );
+    }
+
+    #[test]
+    fn frozen_funds_of_locked_accounts() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,1,2,1.5",
+                "dispute,1,1,",
+                "chargeback,1,1,",
+                "deposit,2,3,2.25",
+                "lock,2,4,",
+                "deposit,3,5,100.0",
+            ])
+            .unwrap();
+        assert_eq!(engine.frozen_funds(), dec("3.75"));
+        assert_eq!(TransactionEngine::new().frozen_funds(), dec("0"));
+    }
+
+    #[test]
+    fn injected_faults_handled_per_policy() {
+        let lines: Vec<String> = (1..=200)
+            .map(|tx_id| format!("deposit,1,{},1.0", tx_id))
+            .collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+
+        let mut engine = TransactionEngine::new()
+            .with_error_policy(ErrorPolicy::Collect)
+            .with_fault_injection(0.1, 42)
+            .unwrap();
+        assert_eq!(engine.process_lines(&lines).unwrap(), 200);
+        let injected = engine.injected_faults();
+        assert_eq!(injected, 24);
+        assert_eq!(engine.errors().len(), injected);
+        let kinds: HashSet<&str> = engine.errors().iter().map(|(_, err)| err.kind()).collect();
+        assert_eq!(
+            kinds,
+            ["Deserialize", "Processing"].iter().copied().collect()
+        );
+        let expected_total = Decimal::from(200 - injected as i64);
+        assert_eq!(engine.accounts.get(&1).unwrap().total, expected_total);
+
+        // The same seed injects the same faults
+        let mut engine = TransactionEngine::new()
+            .with_error_policy(ErrorPolicy::Skip)
+            .with_fault_injection(0.1, 42)
+            .unwrap();
+        engine.process_lines(&lines).unwrap();
+        assert_eq!(engine.skipped_rows(), injected);
+        assert!(engine.errors().is_empty());
+
+        let mut engine = TransactionEngine::new()
+            .with_fault_injection(0.1, 42)
+            .unwrap();
+        let err = engine.process_lines(&lines).unwrap_err();
+        assert_eq!(engine.injected_faults(), 1);
+        assert!(err.downcast_ref::<EngineError>().is_some());
+
+        for rate in [-0.1, 1.5, f64::NAN] {
+            assert!(TransactionEngine::new()
+                .with_fault_injection(rate, 42)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn client_ids_remapped() {
+        let client_id_map: HashMap<u16, u16> = [(10, 1), (11, 1)].iter().copied().collect();
+        let mut engine = TransactionEngine::new().with_client_id_map(client_id_map);
+        engine
+            .process_lines(&[
+                "deposit,10,1,5.0",
+                "deposit,11,2,2.5",
+                "withdrawal,11,3,6.0",
+                "deposit,2,4,1.0",
+                "dispute,10,2,",
+            ])
+            .unwrap();
+        assert!(!engine.accounts.contains_key(&10));
+        assert!(!engine.accounts.contains_key(&11));
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.total, dec("1.5"));
+        assert_eq!(account.held, dec("2.5"));
+        assert_eq!(engine.accounts.get(&2).unwrap().total, dec("1.0"));
+    }
+
+    #[test]
+    fn dispute_actions_capped_per_transaction() {
+        let mut engine = TransactionEngine::new().with_max_dispute_actions(3);
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,1,2,1.0",
+                "dispute,1,1,",
+                "resolve,1,1,",
+                "dispute,1,1,",
+            ])
+            .unwrap();
+        let err = engine.process_lines(&["resolve,1,1,"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::DisputeActionLimit(1))
+        );
+        // The guarded resolve had no effect, other transactions aren't affected
+        assert_eq!(engine.accounts.get(&1).unwrap().held, dec("5.0"));
+        engine
+            .process_lines(&["dispute,1,2,", "resolve,1,2,"])
+            .unwrap();
+        assert_eq!(engine.accounts.get(&1).unwrap().held, dec("5.0"));
+
+        // Ignored actions don't count towards the cap
+        let mut engine = TransactionEngine::new().with_max_dispute_actions(2);
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "resolve,1,1,",
+                "chargeback,1,1,",
+                "resolve,1,1,",
+                "dispute,1,1,",
+                "resolve,1,1,",
+            ])
+            .unwrap();
+        let account = engine.accounts.get(&1).unwrap();
+        assert_eq!(account.available, dec("5.0"));
+        assert_eq!(account.held, dec("0.0"));
+        assert_eq!(engine.dispute_actions.get(&1), Some(&2));
+    }
+
+    #[test]
+    fn alternative_account_store() {
+        #[derive(Debug, Clone, Default)]
+        struct SortedStore(BTreeMap<u16, Account>);
+
+        impl AccountStore for SortedStore {
+            fn get(&self, client_id: u16) -> Option<Account> {
+                self.0.get(&client_id).copied()
+            }
+
+            fn insert(&mut self, client_id: u16, account: Account) -> Option<Account> {
+                self.0.insert(client_id, account)
+            }
+
+            fn iter(&self) -> Box<dyn Iterator<Item = (u16, Account)> + '_> {
+                Box::new(self.0.iter().map(|(id, account)| (*id, *account)))
+            }
+        }
+
+        let lines = [
+            "deposit,2,1,5.0",
+            "deposit,1,2,3.25",
+            "withdrawal,2,3,1.5",
+            "dispute,1,2,",
+            "resolve,1,2,",
+            "dispute,2,1,",
+            "chargeback,2,1,",
+            "deposit,2,4,1.0",
+            "deposit,3,5,0.5",
+        ];
+        let mut engine = TransactionEngine::new();
+        engine.process_lines(&lines).unwrap();
+        let mut other = TransactionEngine::with_account_store(SortedStore::default());
+        other.process_lines(&lines).unwrap();
+
+        assert_eq!(other.debug_state(), engine.debug_state());
+        let order = |accounts: Vec<AccountWithId>| -> Vec<u16> {
+            accounts.iter().map(AccountWithId::id).collect()
+        };
+        assert_eq!(
+            order(other.retrieve_accounts_insertion_order()),
+            order(engine.retrieve_accounts_insertion_order())
+        );
+        assert!(other.account(2).unwrap().locked());
+    }
+
+    #[test]
+    fn lock_report_records_chargebacks() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,1,2,2.5",
+                "deposit,2,3,1.0",
+                "dispute,1,2,",
+                "chargeback,1,2,",
+                "dispute,2,3,",
+                "resolve,2,3,",
+                "lock,2,0,",
+            ])
+            .unwrap();
+
+        let report = engine.lock_report();
+        assert_eq!(report.len(), 1);
+        let record = &report[0];
+        assert_eq!(record.client_id, 1);
+        assert_eq!(record.tx_id, 2);
+        assert_eq!(record.amount, dec("2.5"));
+        assert_eq!(record.account.total(), dec("5.0"));
+        assert_eq!(record.account.held(), dec("0"));
+        assert!(record.account.locked());
+    }
+
+    #[test]
+    fn resolve_shortfall_guarded() {
+        // Client 2 disputes client 1's withdrawal, so client 1 holds nothing when it's resolved
+        let lines = [
+            "deposit,1,1,10.0",
+            "withdrawal,1,2,4.0",
+            "deposit,2,3,1.0",
+            "dispute,2,2,",
+        ];
+
+        let mut engine =
+            TransactionEngine::new().with_resolve_shortfall_policy(ResolveShortfallPolicy::Reject);
+        engine.process_lines(&lines).unwrap();
+        let err = engine.process_lines(&["resolve,1,2,"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::ResolveExceedsHeld(2))
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available(), dec("6.0"));
+        assert_eq!(account.total(), dec("6.0"));
+
+        let mut engine =
+            TransactionEngine::new().with_resolve_shortfall_policy(ResolveShortfallPolicy::Clamp);
+        engine.process_lines(&lines).unwrap();
+        engine.process_lines(&["resolve,1,2,"]).unwrap();
+        let account = engine.account(1).unwrap();
+        assert!(account.available() <= account.total());
+        assert_eq!(account.held(), dec("0"));
+        assert_eq!(account.total(), dec("6.0"));
+
+        // Without a guard available ends up above total
+        let mut engine = TransactionEngine::new();
+        engine.process_lines(&lines).unwrap();
+        engine.process_lines(&["resolve,1,2,"]).unwrap();
+        let account = engine.account(1).unwrap();
+        assert!(account.available() > account.total());
     }
 
-    /// Retrieve an iterator of all the accounts including their Ids. This function retrieves the
-    /// state of all accounts as of a particular point in time. The account information is given
-    /// in the form of immutable copies as at the time the iterator is iterated.
-    pub fn retrieve_accounts(&self) -> impl Iterator<Item = AccountWithId> + '_ {
-        self.accounts.iter().map(|(id, account)| AccountWithId {
-            // Copy out the entries values
-            id: *id,
-            account: *account,
-        })
+    #[test]
+    fn grouped_output_streamed_per_client() {
+        let input = "type,client,tx,amount
+deposit,2,1,5.0
+withdrawal,2,2,1.5
+deposit,1,3,2.0
+dispute,1,3,
+deposit,3,4,1.0
+";
+        let mut output = Vec::new();
+        let mut engine = TransactionEngine::new();
+        let rows = engine
+            .process_reader_grouped(input.as_bytes(), &mut output, io::sink())
+            .unwrap();
+        assert_eq!(rows, 5);
+        // Each client is written as its group ends, so in the order they appear in the input
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked
+2,3.5000,0.0000,3.5000,false
+1,0.0000,2.0000,2.0000,false
+3,1.0000,0.0000,1.0000,false
+"
+        );
+
+        // Nothing is written when the input has no header
+        let mut output = Vec::new();
+        let err = TransactionEngine::new()
+            .process_reader_grouped("deposit,1,1,1.0\n".as_bytes(), &mut output, io::sink())
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::MissingHeader)
+        );
+        assert!(output.is_empty());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::engine::TransactionType::Chargeback;
-    use crate::engine::TransactionType::Deposit;
-    use crate::engine::TransactionType::Dispute;
-    use crate::engine::TransactionType::Resolve;
-    use crate::engine::TransactionType::Withdrawal;
-    use rust_decimal::prelude::FromStr;
+    #[test]
+    fn chargeback_after_overdraft() {
+        // The deposit is spent along with some of the overdraft before it's charged back
+        let lines = [
+            "deposit,1,1,5.0",
+            "withdrawal,1,2,7.0",
+            "deposit,2,3,1.0",
+            "dispute,1,1,",
+        ];
+        let chargeback = "chargeback,1,1,";
 
-    fn dec(value: &str) -> Decimal {
-        Decimal::from_str(value).unwrap()
+        let mut engine = TransactionEngine::new().with_overdraft_limit(dec("3.0"));
+        engine.process_lines(&lines).unwrap();
+        engine.process_lines(&[chargeback]).unwrap();
+        assert_eq!(engine.account(1).unwrap().total(), dec("-7.0"));
+        assert!(engine.negative_total_chargebacks().is_empty());
+
+        let mut engine = TransactionEngine::new()
+            .with_overdraft_limit(dec("3.0"))
+            .with_negative_total_policy(NegativeTotalPolicy::Flag);
+        engine.process_lines(&lines).unwrap();
+        engine.process_lines(&[chargeback]).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.total(), dec("-7.0"));
+        assert!(account.locked());
+        assert_eq!(engine.negative_total_chargebacks(), vec![1]);
+
+        let mut engine = TransactionEngine::new()
+            .with_overdraft_limit(dec("3.0"))
+            .with_negative_total_policy(NegativeTotalPolicy::Reject);
+        engine.process_lines(&lines).unwrap();
+        let err = engine.process_lines(&[chargeback]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::ChargebackExceedsTotal(1))
+        );
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.total(), dec("-2.0"));
+        assert_eq!(account.held(), dec("5.0"));
+        assert!(!account.locked());
     }
 
     #[test]
-    fn can_deposit_and_withdraw() {
+    fn net_deposit_ledger() {
         let mut engine = TransactionEngine::new();
-        let acct_id = 1;
         engine
-            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,1,2,2.5",
+                "deposit,2,3,1.0",
+                "withdrawal,2,4,0.5",
+                "dispute,1,2,",
+                "chargeback,1,2,",
+            ])
             .unwrap();
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        assert_eq!(current_acct.available, dec("1.0"));
+        assert_eq!(
+            engine.deposit_ledger(DepositReporting::Gross),
+            vec![(1, dec("7.5")), (2, dec("1.0"))]
+        );
+        assert_eq!(
+            engine.deposit_ledger(DepositReporting::Net),
+            vec![(1, dec("5.0")), (2, dec("1.0"))]
+        );
+    }
+
+    #[test]
+    fn accounts_written_to_shards() {
+        let mut engine = TransactionEngine::new();
         engine
-            .process_transaction(Transaction::from(Withdrawal, acct_id, 1, Some("0.1234")))
+            .process_lines(&[
+                "deposit,1,1,1.0",
+                "deposit,4,2,4.0",
+                "deposit,3,3,3.0",
+                "deposit,7,4,7.0",
+            ])
             .unwrap();
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        assert_eq!(current_acct.available, dec("0.8766"));
+        let dir = std::env::temp_dir().join(format!("shards-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        engine.write_accounts_sharded(&dir, 3).unwrap();
+
+        let shard = |idx: usize| std::fs::read_to_string(dir.join(format!("accounts-{}.csv", idx)));
+        assert_eq!(
+            shard(0).unwrap(),
+            "client,available,held,total,locked\n\
+            3,3.0000,0.0000,3.0000,false\n"
+        );
+        assert_eq!(
+            shard(1).unwrap(),
+            "client,available,held,total,locked\n\
+            1,1.0000,0.0000,1.0000,false\n\
+            4,4.0000,0.0000,4.0000,false\n\
+            7,7.0000,0.0000,7.0000,false\n"
+        );
+        assert_eq!(shard(2).unwrap(), "client,available,held,total,locked\n");
+        assert!(shard(3).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
-    fn chargeback_deposit_flow() {
+    fn median_account_balance() {
         let mut engine = TransactionEngine::new();
-        let acct_id = 1;
+        assert_eq!(engine.median_balance(), None);
         engine
-            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .process_lines(&["deposit,1,1,7.0", "deposit,2,2,1.5", "deposit,3,3,4.0"])
             .unwrap();
+        assert_eq!(engine.median_balance(), Some(dec("4.0")));
+        engine.process_lines(&["deposit,4,4,2.0"]).unwrap();
+        assert_eq!(engine.median_balance(), Some(dec("3.0")));
+    }
+
+    #[test]
+    fn disputes_in_later_source() {
+        let deposits = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,1.0
+deposit,1,4,2.0
+";
+        let disputes = "type,client,tx
+dispute,1,1
+dispute,2,2
+chargeback,2,2
+dispute,1,3
+resolve,1,3
+";
+        let mut engine = TransactionEngine::new();
+        assert_eq!(engine.process_reader(deposits.as_bytes()).unwrap(), 4);
+        assert_eq!(engine.process_reader(disputes.as_bytes()).unwrap(), 5);
+
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available(), dec("1.0"));
+        assert_eq!(account.held(), dec("5.0"));
+        assert_eq!(account.total(), dec("6.0"));
+        let account = engine.account(2).unwrap();
+        assert_eq!(account.total(), dec("0"));
+        assert!(account.locked());
+        assert_eq!(engine.resolves_without_dispute(), 0);
+    }
+
+    #[test]
+    fn transactions_listed_by_type() {
+        let mut engine = TransactionEngine::new();
         engine
-            .process_transaction(Transaction::from(Dispute, acct_id, 1, Option::<&str>::None))
+            .process_lines(&[
+                "deposit,1,3,5.0",
+                "withdrawal,1,2,1.0",
+                "deposit,2,1,2.0",
+                "withdrawal,2,4,9.0",
+                "dispute,1,3,",
+            ])
             .unwrap();
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        // Available and held should have been modified due to the dispute
-        assert_eq!(current_acct.available, dec("0"));
-        assert_eq!(current_acct.held, dec("1.0"));
-        assert_eq!(engine.disputed_transactions.contains(&1), true);
+        let ids = |ty| -> Vec<TxId> {
+            engine
+                .transactions_of_type(ty)
+                .iter()
+                .map(|tx| tx.tx_id())
+                .collect()
+        };
+        assert_eq!(ids(Deposit), vec![1, 3]);
+        // The withdrawal with insufficient funds was never applied
+        assert_eq!(ids(Withdrawal), vec![2]);
+        assert!(ids(Dispute).is_empty());
+    }
+
+    #[test]
+    fn chargeback_without_locking() {
+        let mut engine = TransactionEngine::new().with_lock_on_chargeback(false);
         engine
-            .process_transaction(Transaction::from(
-                Chargeback,
-                acct_id,
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,1,2,2.0",
+                "dispute,1,2,",
+                "chargeback,1,2,",
+            ])
+            .unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.total(), dec("5.0"));
+        assert!(!account.locked());
+        assert_eq!(engine.chargeback_count(1), 1);
+        assert!(engine.lock_report().is_empty());
+
+        engine
+            .process_lines(&["withdrawal,1,3,1.5", "deposit,1,4,0.5"])
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().total(), dec("4.0"));
+    }
+
+    #[test]
+    fn simulate_batch_for_one_client() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&["deposit,1,1,5.0", "deposit,2,2,1.0"])
+            .unwrap();
+        let batch = [
+            Transaction::from(Deposit, 1, 3, Some("2.5")),
+            Transaction::from(Deposit, 2, 4, Some("9.0")),
+            Transaction::from(Withdrawal, 1, 5, Some("4.0")),
+        ];
+        let simulated = engine.simulate_batch_for_client(1, &batch);
+        assert_eq!(simulated.id(), 1);
+        assert_eq!(simulated.available(), dec("3.5"));
+        assert_eq!(simulated.total(), dec("3.5"));
+        // Nothing was applied to the engine
+        assert_eq!(engine.account(1).unwrap().total(), dec("5.0"));
+        assert_eq!(engine.account(2).unwrap().total(), dec("1.0"));
+
+        let simulated = engine.simulate_batch_for_client(3, &batch);
+        assert_eq!(simulated.total(), dec("0"));
+    }
+
+    #[test]
+    fn output_bom() {
+        let mut engine = TransactionEngine::new();
+        engine.process_lines(&["deposit,1,1,5.0"]).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts(&mut output).unwrap();
+        assert!(output.starts_with(b"client,"));
+
+        let engine = engine.with_output_bom(true);
+        let mut output = Vec::new();
+        engine.write_accounts(&mut output).unwrap();
+        assert_eq!(
+            output,
+            b"\xEF\xBB\xBFclient,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n"
+        );
+        let mut output = Vec::new();
+        engine
+            .write_accounts_columns(&mut output, &[AccountColumn::Client])
+            .unwrap();
+        assert_eq!(output, b"\xEF\xBB\xBFclient\n1\n");
+    }
+
+    #[test]
+    fn output_schema_version() {
+        let mut engine = TransactionEngine::new().with_output_schema_version(true);
+        engine.process_lines(&["deposit,1,1,5.0"]).unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap(),
+            "# schema: v1\nclient,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n"
+        );
+        // Output with the comment can still be read back in
+        let restored = TransactionEngine::from_accounts_csv(output.as_slice()).unwrap();
+        assert_eq!(restored.account(1).unwrap().total(), dec("5.0"));
+
+        let engine = engine.with_output_schema_version(false);
+        let mut output = Vec::new();
+        engine.write_accounts(&mut output).unwrap();
+        assert!(output.starts_with(b"client,"));
+    }
+
+    #[test]
+    fn largest_held_amount() {
+        let mut engine = TransactionEngine::new();
+        engine.process_lines(&["deposit,1,1,5.0"]).unwrap();
+        assert_eq!(engine.largest_held(), None);
+        engine
+            .process_lines(&[
+                "deposit,2,2,7.5",
+                "deposit,3,3,7.5",
+                "deposit,4,4,2.0",
+                "dispute,1,1,",
+                "dispute,3,3,",
+                "dispute,4,4,",
+            ])
+            .unwrap();
+        assert_eq!(engine.largest_held(), Some((3, dec("7.5"))));
+        engine.process_lines(&["dispute,2,2,"]).unwrap();
+        assert_eq!(engine.largest_held(), Some((2, dec("7.5"))));
+    }
+
+    #[test]
+    fn undo_processed_file() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&["deposit,1,1,5.0", "deposit,2,2,1.0"])
+            .unwrap();
+        let file = "type,client,tx,amount
+deposit,1,3,2.5
+withdrawal,1,4,1.0
+withdrawal,2,5,9.0
+deposit,3,6,4.0
+dispute,1,1,
+";
+        engine.process_reader(file.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().held(), dec("5.0"));
+
+        // The withdrawal with insufficient funds was never applied so isn't reversed
+        assert_eq!(engine.undo_file(file.as_bytes()).unwrap(), 3);
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available(), dec("0"));
+        assert_eq!(account.held(), dec("5.0"));
+        assert_eq!(account.total(), dec("5.0"));
+        assert_eq!(engine.account(2).unwrap().total(), dec("1.0"));
+        assert_eq!(engine.account(3).unwrap().total(), dec("0"));
+        // Undoing again has nothing left to reverse
+        assert_eq!(engine.undo_file(file.as_bytes()).unwrap(), 0);
+    }
+
+    #[test]
+    fn undo_file_is_read_like_processed_input() {
+        let mut engine = TransactionEngine::new().with_type_alias("dep", TransactionType::Deposit);
+        engine
+            .process_lines(&["dep,1,1,5.0", "deposit,1,2,1.0"])
+            .unwrap();
+        let err = engine
+            .undo_file("deposit,1,1,5.0\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::MissingHeader)
+        );
+        let err = engine
+            .undo_file("type,client,tx,amount\ndeposit,1,,1.0\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::MissingTxId)
+        );
+        let file = "type,client,tx,amount\ndep,1,1,5.0\n";
+        assert_eq!(engine.undo_file(file.as_bytes()).unwrap(), 1);
+        assert_eq!(engine.account(1).unwrap().total(), dec("1.0"));
+    }
+
+    #[test]
+    fn undo_refused_on_locked_or_overdrawn_accounts() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "withdrawal,1,2,4.0",
+                "deposit,2,3,2.0",
+                "deposit,2,4,1.0",
+                "dispute,2,4,",
+                "chargeback,2,4,",
+            ])
+            .unwrap();
+        let file = "type,client,tx,amount\ndeposit,1,1,5.0\n";
+        let err = engine.undo_file(file.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::UndoRefused {
+                tx_id: 1,
+                reason: "it would overdraw the account"
+            })
+        );
+        assert_eq!(engine.account(1).unwrap().total(), dec("1.0"));
+
+        // Under a lenient policy the refusals are reported and the rest carries on
+        let mut engine = engine.with_error_policy(ErrorPolicy::Collect);
+        let file = "type,client,tx,amount\ndeposit,2,3,2.0\nwithdrawal,1,2,4.0\n";
+        assert_eq!(engine.undo_file(file.as_bytes()).unwrap(), 1);
+        assert_eq!(
+            engine.errors(),
+            &[(
                 1,
-                Option::<&str>::None,
-            ))
+                EngineError::UndoRefused {
+                    tx_id: 3,
+                    reason: "the account is locked"
+                }
+            )]
+        );
+        assert_eq!(engine.account(1).unwrap().total(), dec("5.0"));
+        assert_eq!(engine.account(2).unwrap().total(), dec("2.0"));
+    }
+
+    #[test]
+    fn process_pages() {
+        let pages = [
+            vec![
+                Transaction::from(Deposit, 1, 1, Some("5.0")),
+                Transaction::from(Deposit, 2, 2, Some("1.0")),
+            ],
+            vec![
+                Transaction::from(Withdrawal, 1, 3, Some("1.5")),
+                Transaction::from(Dispute, 2, 2, Option::<&str>::None),
+            ],
+        ];
+        let mut requested = Vec::new();
+        let mut engine = TransactionEngine::new();
+        let rows = engine
+            .process_paginated(|page| {
+                requested.push(page);
+                pages.get(page).cloned()
+            })
             .unwrap();
-        // Now that a chargeback has occurred the account should be empty and locked
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        assert_eq!(current_acct.available, dec("0"));
-        assert_eq!(current_acct.held, dec("0"));
-        assert_eq!(current_acct.locked, true);
-        assert_eq!(engine.disputed_transactions.is_empty(), true);
+        assert_eq!(rows, 4);
+        assert_eq!(requested, vec![0, 1, 2]);
+        assert_eq!(engine.account(1).unwrap().total(), dec("3.5"));
+        assert_eq!(engine.account(2).unwrap().held(), dec("1.0"));
+    }
+
+    #[test]
+    fn control_account_mirrors_net_flows() {
+        let mut engine = TransactionEngine::new().with_control_account(999);
         engine
-            .process_transaction(Transaction::from(Deposit, acct_id, 2, Some("1.0")))
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,2,2,3.0",
+                "withdrawal,1,3,1.5",
+                "withdrawal,2,4,10.0",
+                "dispute,2,2,",
+                "chargeback,2,2,",
+            ])
             .unwrap();
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        // Since we are locked we shouldn't be able to deposit anymore
-        assert_eq!(current_acct.total, dec("0"));
+        let err = engine.process_lines(&["deposit,999,5,1.0"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::ClientNotAllowed(999))
+        );
+
+        let control = engine.account(999).unwrap();
+        assert_eq!(control.total(), dec("6.5"));
+        assert_eq!(control.available(), dec("6.5"));
+        assert!(!control.locked());
     }
 
     #[test]
-    fn resolve_deposit_flow() {
+    fn control_account_left_out_of_reports() {
+        let mut engine = TransactionEngine::new()
+            .with_control_account(999)
+            .with_max_system_held_ratio(dec("0.3"));
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,2,2,3.0",
+                "deposit,3,3,1.0",
+                "dispute,2,2,",
+                "dispute,3,3,",
+                "chargeback,3,3,",
+            ])
+            .unwrap();
+        assert_eq!(engine.account(999).unwrap().total(), dec("9.0"));
+        // Counting the control account would halve the system's held ratio
+        assert!(engine.systemic_dispute_flagged());
+        assert_eq!(engine.frozen_funds(), dec("0"));
+        assert_eq!(engine.median_balance(), Some(dec("3.0")));
+        assert_eq!(engine.largest_held(), Some((2, dec("3.0"))));
+        let sampled: Vec<u16> = engine
+            .sample_accounts(10, 7)
+            .iter()
+            .map(|acct| acct.id)
+            .collect();
+        assert_eq!(sampled, vec![1, 2, 3]);
+        assert_eq!(engine.reconcile(dec("0")).accounts, 3);
+
+        // Undoing a file takes its transactions back out of the control account too
+        let file = "type,client,tx,amount\ndeposit,1,1,5.0\n";
+        assert_eq!(engine.undo_file(file.as_bytes()).unwrap(), 1);
+        assert_eq!(engine.account(999).unwrap().total(), dec("4.0"));
+    }
+
+    #[test]
+    fn accounts_as_json_lines() {
         let mut engine = TransactionEngine::new();
-        let acct_id = 1;
         engine
-            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,2,2,1.25",
+                "dispute,2,2,",
+                "deposit,3,3,2.0",
+            ])
             .unwrap();
+        let mut output = Vec::new();
+        engine.write_accounts_jsonl(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        let mut accounts: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        accounts.sort_by_key(|acct| acct["client"].as_u64());
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(
+            accounts[1],
+            serde_json::json!({
+                "client": 2,
+                "available": "0.0000",
+                "held": "1.2500",
+                "total": "1.2500",
+                "locked": false,
+            })
+        );
+    }
+
+    #[test]
+    fn only_deposits_and_withdrawals_disputable() {
+        let mut engine = TransactionEngine::new();
         engine
-            .process_transaction(Transaction::from(Dispute, acct_id, 1, Option::<&str>::None))
+            .process_lines(&["deposit,1,1,5.0", "withdrawal,1,2,1.0"])
             .unwrap();
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        // Available and held should have been modified due to the dispute
-        assert_eq!(current_acct.available, dec("0"));
-        assert_eq!(current_acct.held, dec("1.0"));
-        assert_eq!(engine.disputed_transactions.contains(&1), true);
+        // Dispute messages are never stored, so force one in as a future change might
         engine
-            .process_transaction(Transaction::from(Resolve, acct_id, 1, Option::<&str>::None))
+            .transactions
+            .insert(3, Transaction::from(Dispute, 1, 3, Option::<&str>::None));
+        for line in &["dispute,1,3,", "resolve,1,3,", "chargeback,1,3,"] {
+            let err = engine.process_lines(&[line]).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<EngineError>(),
+                Some(&EngineError::NonDisputableType(3))
+            );
+        }
+        engine
+            .process_lines(&["dispute,1,1,", "dispute,1,2,"])
             .unwrap();
-        // Now that a resolve has occurred the account should have funds restored
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        assert_eq!(current_acct.available, dec("1.0"));
-        assert_eq!(current_acct.held, dec("0"));
-        assert_eq!(current_acct.locked, false);
-        assert_eq!(engine.disputed_transactions.is_empty(), true);
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.held(), dec("6.0"));
+        assert!(!account.locked());
+    }
+
+    #[test]
+    fn open_disputes_capped_engine_wide() {
+        let mut engine = TransactionEngine::new().with_max_total_open_disputes(2);
         engine
-            .process_transaction(Transaction::from(Deposit, acct_id, 2, Some("1.0")))
+            .process_lines(&[
+                "deposit,1,1,1.0",
+                "deposit,2,2,2.0",
+                "deposit,3,3,3.0",
+                "dispute,1,1,",
+                "dispute,2,2,",
+            ])
             .unwrap();
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        // Additional deposits should be fine
-        assert_eq!(current_acct.available, dec("2.0"));
+        let err = engine.process_lines(&["dispute,3,3,"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::OpenDisputeLimit(3))
+        );
+        assert_eq!(engine.account(3).unwrap().held(), dec("0"));
+        // Settling an open dispute makes room for another
+        engine
+            .process_lines(&["resolve,1,1,", "dispute,3,3,"])
+            .unwrap();
+        assert_eq!(engine.account(3).unwrap().held(), dec("3.0"));
     }
 
     #[test]
-    fn resolve_withdrawal_flow() {
+    fn process_and_validate_in_one_pass() {
+        let mut engine = TransactionEngine::new().with_error_policy(ErrorPolicy::Skip);
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     deposit,1,,1.0\n\
+                     withdrawal,2,x,1.0\n\
+                     deposit,2,3,2.0\n\
+                     deposit,2,3,0.0\n";
+        let (accounts, report) = engine.process_and_validate_reader(input.as_bytes());
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].id, 1);
+        assert_eq!(accounts[0].total(), dec("5.0"));
+        assert_eq!(accounts[1].id, 2);
+        assert_eq!(accounts[1].total(), dec("2.0"));
+        assert_eq!(report.rows, 5);
+        assert!(report.complete);
+        let kinds: Vec<(usize, &str)> = report
+            .issues
+            .iter()
+            .map(|(row, err)| (*row, err.kind()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [(2, "MissingTxId"), (3, "Deserialize"), (5, "ZeroDeposit")]
+        );
+        // Skipped records are still only counted by the engine itself
+        assert_eq!(engine.skipped_rows(), 3);
+        assert!(engine.errors().is_empty());
+
         let mut engine = TransactionEngine::new();
-        let acct_id = 1;
+        let (accounts, report) = engine.process_and_validate_reader(input.as_bytes());
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(report.rows, 2);
+        assert_eq!(report.issues, [(2, EngineError::MissingTxId)]);
+        assert!(!report.complete);
+
+        let (_, report) = engine.process_and_validate_reader("deposit,1,9,1.0\n".as_bytes());
+        assert_eq!(report.issues, [(0, EngineError::MissingHeader)]);
+    }
+
+    #[test]
+    fn duplicate_resolves_are_no_ops() {
+        let none = Option::<&str>::None;
+        let mut engine = TransactionEngine::new().with_strict_settlement(true);
+        let (outcomes, accounts) = engine.process_full(vec![
+            Transaction::from(Deposit, 1, 1, Some("5.0")),
+            Transaction::from(Dispute, 1, 1, none),
+            Transaction::from(Resolve, 1, 1, none),
+            Transaction::from(Resolve, 1, 1, none),
+        ]);
+        assert_eq!(
+            outcomes,
+            [
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::DuplicateResolve,
+            ]
+        );
+        assert_eq!(accounts[0].available(), dec("5.0"));
+        assert_eq!(accounts[0].held(), dec("0"));
+        assert_eq!(engine.duplicate_resolves(), 1);
+        assert_eq!(engine.resolves_without_dispute(), 0);
+        // A fresh dispute makes the next resolve a real one again
+        let (outcomes, _) = engine.process_full(vec![
+            Transaction::from(Dispute, 1, 1, none),
+            Transaction::from(Resolve, 1, 1, none),
+            Transaction::from(Chargeback, 1, 1, none),
+        ]);
+        assert_eq!(outcomes[1], ProcessOutcome::Applied);
+        assert_eq!(
+            outcomes[2],
+            ProcessOutcome::Failed(EngineError::ResolveWithoutDispute(1))
+        );
+        assert_eq!(engine.duplicate_resolves(), 1);
+    }
+
+    #[test]
+    fn total_liabilities_is_net_flows() {
+        let mut engine = TransactionEngine::new().with_control_account(999);
+        assert_eq!(engine.total_liabilities(), dec("0"));
         engine
-            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .process_lines(&[
+                "deposit,1,1,10.0",
+                "deposit,2,2,2.5",
+                "withdrawal,1,3,4.0",
+                "deposit,3,4,1.25",
+                "withdrawal,2,5,0.5",
+            ])
             .unwrap();
+        assert_eq!(engine.total_liabilities(), dec("9.25"));
+        assert_eq!(engine.account(999).unwrap().total(), dec("9.25"));
+    }
+
+    #[test]
+    fn dispute_straight_after_deposit() {
+        let mut engine = TransactionEngine::new();
         engine
-            .process_transaction(Transaction::from(Withdrawal, acct_id, 2, Some("1.0")))
+            .process_lines(&["deposit,1,1,3.0", "dispute,1,1,"])
+            .unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available(), dec("0"));
+        assert_eq!(account.held(), dec("3.0"));
+
+        // The same holds a record at a time through a processor
+        let mut engine = TransactionEngine::new();
+        let input = "type,client,tx,amount\ndeposit,1,1,3.0\ndispute,1,1,\n";
+        let mut processor = engine.processor(input.as_bytes()).unwrap();
+        assert!(processor.step().unwrap());
+        assert!(processor.step().unwrap());
+        assert_eq!(processor.engine().account(1).unwrap().held(), dec("3.0"));
+    }
+
+    #[test]
+    fn seeded_account_samples() {
+        let mut engine = TransactionEngine::new();
+        let lines: Vec<String> = (1..=100)
+            .map(|id| format!("deposit,{},{},1.0", id, id))
+            .collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        engine.process_lines(&lines).unwrap();
+        let ids = |sample: Vec<AccountWithId>| -> Vec<u16> {
+            sample.into_iter().map(|acct| acct.id).collect()
+        };
+        let sample = ids(engine.sample_accounts(10, 42));
+        assert_eq!(sample.len(), 10);
+        assert!(sample.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(sample, ids(engine.fork().sample_accounts(10, 42)));
+        assert_ne!(sample, ids(engine.sample_accounts(10, 43)));
+        assert_eq!(engine.sample_accounts(1000, 42).len(), 100);
+        assert!(TransactionEngine::new().sample_accounts(5, 1).is_empty());
+    }
+
+    #[test]
+    fn diff_two_inputs() {
+        let a = "type,client,tx,amount\n\
+            deposit,1,1,5.0\n\
+            deposit,2,2,3.0\n\
+            deposit,3,3,1.0\n";
+        let b = "type,client,tx,amount\n\
+            deposit,1,1,5.0\n\
+            deposit,2,2,3.5\n\
+            dispute,2,2,\n\
+            deposit,4,4,2.0\n\
+            dispute,4,4,\n\
+            chargeback,4,4,\n";
+        let diffs = diff_files(a.as_bytes(), b.as_bytes()).unwrap();
+        assert_eq!(
+            diffs,
+            [
+                AccountDiff {
+                    id: 2,
+                    available: dec("-3.0"),
+                    held: dec("3.5"),
+                    total: dec("0.5"),
+                    locked: None,
+                },
+                AccountDiff {
+                    id: 3,
+                    available: dec("-1.0"),
+                    held: dec("0"),
+                    total: dec("-1.0"),
+                    locked: None,
+                },
+                AccountDiff {
+                    id: 4,
+                    available: dec("0"),
+                    held: dec("0"),
+                    total: dec("0"),
+                    locked: Some(true),
+                },
+            ]
+        );
+        assert!(diff_files(a.as_bytes(), a.as_bytes()).unwrap().is_empty());
+        assert!(diff_files(a.as_bytes(), "deposit,1,1,1.0".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn amounts_with_trailing_characters_rejected() {
+        let mut engine = TransactionEngine::new();
+        let err = engine.process_lines(&["deposit,1,1,1.00abc"]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EngineError>(),
+            Some(&EngineError::MalformedAmount("1.00abc".to_string()))
+        );
+        assert!(format!("{:#}", err).contains("`1.00abc`"));
+        assert_eq!(engine.total_liabilities(), dec("0"));
+        // Amounts that aren't numbers at all still fail as before
+        let err = engine.process_lines(&["deposit,1,2,abc"]).unwrap_err();
+        assert_eq!(err.downcast_ref::<EngineError>(), None);
+        assert!(has_trailing_characters("-2.5x"));
+        assert!(!has_trailing_characters("1.2.3"));
+        assert!(!has_trailing_characters("x1"));
+    }
+
+    #[test]
+    fn disputed_accounts_stay_flagged() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&[
+                "deposit,1,1,1.0",
+                "deposit,2,2,2.0",
+                "deposit,3,3,3.0",
+                "dispute,2,2,",
+                "dispute,3,99,",
+            ])
             .unwrap();
+        assert_eq!(engine.accounts_ever_disputed(), [2]);
         engine
-            .process_transaction(Transaction::from(Dispute, acct_id, 2, Option::<&str>::None))
+            .process_lines(&["resolve,2,2,", "dispute,1,1,", "chargeback,1,1,"])
             .unwrap();
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        // Available and held should have been modified due to the dispute
-        assert_eq!(current_acct.available, dec("0"));
-        assert_eq!(current_acct.held, dec("1.0"));
-        assert_eq!(current_acct.total, dec("1.0"));
-        assert_eq!(engine.disputed_transactions.contains(&2), true);
+        assert_eq!(engine.account(2).unwrap().held(), dec("0"));
+        assert_eq!(engine.accounts_ever_disputed(), [1, 2]);
+    }
+
+    #[test]
+    fn out_of_lifecycle_actions_rejected() {
+        let mut engine = TransactionEngine::new()
+            .with_lifecycle_enforcement(true)
+            .with_lock_on_chargeback(false);
         engine
-            .process_transaction(Transaction::from(Resolve, acct_id, 2, Option::<&str>::None))
+            .process_lines(&["deposit,1,1,5.0", "deposit,2,2,1.0"])
             .unwrap();
-        // Now that a resolve has occurred the account should have funds restored
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        assert_eq!(current_acct.available, dec("0"));
-        assert_eq!(current_acct.held, dec("0"));
-        assert_eq!(current_acct.locked, false);
-        assert_eq!(engine.disputed_transactions.is_empty(), true);
+        let assert_rejected = |engine: &mut TransactionEngine, line: &str, action: &'static str| {
+            let err = engine.process_lines(&[line]).unwrap_err();
+            let tx_id = line.split(',').nth(2).unwrap().parse().unwrap();
+            assert_eq!(
+                err.downcast_ref::<EngineError>(),
+                Some(&EngineError::LifecycleViolation { tx_id, action })
+            );
+        };
+        assert_rejected(&mut engine, "resolve,1,1,", "resolve");
+        assert_rejected(&mut engine, "chargeback,1,1,", "chargeback");
+        // Another client can't dispute the transaction
+        assert_rejected(&mut engine, "dispute,2,1,", "dispute");
+        engine.process_lines(&["dispute,1,1,"]).unwrap();
+        assert_rejected(&mut engine, "dispute,1,1,", "dispute");
+        assert_eq!(engine.account(1).unwrap().held(), dec("5.0"));
         engine
-            .process_transaction(Transaction::from(Deposit, acct_id, 3, Some("1.0")))
+            .process_lines(&["resolve,1,1,", "resolve,1,1,", "dispute,1,1,"])
             .unwrap();
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        // Additional deposits should be fine
-        assert_eq!(current_acct.available, dec("1.0"));
+        assert_eq!(engine.duplicate_resolves(), 1);
+        engine.process_lines(&["chargeback,1,1,"]).unwrap();
+        assert_rejected(&mut engine, "dispute,1,1,", "dispute");
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.total(), dec("0"));
+        assert_eq!(account.held(), dec("0"));
+        // Unknown transactions are still ignored
+        engine.process_lines(&["resolve,1,99,"]).unwrap();
     }
 
     #[test]
-    fn withdraw_too_much() {
+    fn global_freeze() {
         let mut engine = TransactionEngine::new();
-        let acct_id = 1;
         engine
-            .process_transaction(Transaction::from(Deposit, acct_id, 1, Some("1.0")))
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "deposit,2,2,3.0",
+                "dispute,2,2,",
+                "chargeback,2,2,",
+            ])
             .unwrap();
+        engine.freeze_all();
+        assert!(engine.is_frozen());
+        let (outcomes, accounts) = engine.process_full(vec![
+            Transaction::from(Deposit, 1, 3, Some("1.0")),
+            Transaction::from(Withdrawal, 1, 4, Some("1.0")),
+        ]);
+        assert_eq!(
+            outcomes,
+            [ProcessOutcome::AccountLocked, ProcessOutcome::AccountLocked]
+        );
+        assert!(accounts.iter().all(|acct| acct.locked()));
+        assert_eq!(accounts[0].total(), dec("5.0"));
+        // An unlock in the input doesn't lift the freeze for its client
+        let (outcomes, _) = engine.process_full(vec![
+            Transaction::from(TransactionType::Unlock, 1, 0, Option::<&str>::None),
+            Transaction::from(Deposit, 1, 6, Some("1.0")),
+        ]);
+        assert_eq!(
+            outcomes,
+            [ProcessOutcome::AccountLocked, ProcessOutcome::AccountLocked]
+        );
+        assert!(engine.account(1).unwrap().locked());
+
+        engine.unfreeze_all();
+        assert!(!engine.is_frozen());
+        engine.process_lines(&["deposit,1,5,1.0"]).unwrap();
+        assert_eq!(engine.account(1).unwrap().total(), dec("6.0"));
+        // The account locked by its chargeback stays locked
+        assert!(engine.account(2).unwrap().locked());
+
+        let mut engine = TransactionEngine::new().with_reject_while_frozen(true);
+        engine.process_lines(&["deposit,1,1,5.0"]).unwrap();
+        engine.freeze_all();
+        for line in &["deposit,3,2,1.0", "unlock,1,0,"] {
+            let err = engine.process_lines(&[line]).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<EngineError>(),
+                Some(&EngineError::GloballyFrozen)
+            );
+        }
+        assert!(engine.account(3).is_none());
+        engine.unfreeze_all();
+        engine.process_lines(&["deposit,3,2,1.0"]).unwrap();
+        assert_eq!(engine.account(3).unwrap().total(), dec("1.0"));
+    }
+
+    #[test]
+    fn dispute_only_accounts_output() {
+        let lines = [
+            "deposit,1,1,5.0",
+            "dispute,2,7,",
+            "withdrawal,3,2,1.0",
+            "deposit,4,3,2.0",
+            "withdrawal,4,4,2.0",
+        ];
+        let output_with = |output: NoActivityOutput| -> String {
+            let mut engine = TransactionEngine::new().with_no_activity_output(output);
+            engine.process_lines(&lines).unwrap();
+            let accounts = engine.retrieve_accounts_insertion_order();
+            let mut written = Vec::new();
+            engine
+                .write_selected_accounts(&mut written, accounts)
+                .unwrap();
+            String::from_utf8(written).unwrap()
+        };
+        assert_eq!(output_with(NoActivityOutput::Include).lines().count(), 5);
+        // The account that only saw a dispute is left out, as is the one whose only withdrawal
+        // was rejected, but not the one that was emptied
+        assert_eq!(
+            output_with(NoActivityOutput::Omit),
+            "client,available,held,total,locked\n\
+             1,5.0000,0.0000,5.0000,false\n\
+             4,0.0000,0.0000,0.0000,false\n"
+        );
+        assert_eq!(
+            output_with(NoActivityOutput::Mark),
+            "client,available,held,total,locked,no_activity\n\
+             1,5.0000,0.0000,5.0000,false,false\n\
+             2,0.0000,0.0000,0.0000,false,true\n\
+             3,0.0000,0.0000,0.0000,false,true\n\
+             4,0.0000,0.0000,0.0000,false,false\n"
+        );
+
+        // The other output formats honour the setting too
+        let mut engine = TransactionEngine::new().with_no_activity_output(NoActivityOutput::Omit);
+        engine.process_lines(&lines).unwrap();
+        let mut output = Vec::new();
         engine
-            .process_transaction(Transaction::from(Withdrawal, acct_id, 1, Some("2.0")))
+            .write_accounts_columns(&mut output, &[AccountColumn::Client])
             .unwrap();
-        let current_acct = engine.accounts.get(&acct_id).unwrap();
-        // The withdrawal should not have had an effect
-        assert_eq!(current_acct.available, dec("1.0"));
+        let mut clients: Vec<&str> = std::str::from_utf8(&output).unwrap().lines().collect();
+        clients.sort_unstable();
+        assert_eq!(clients, vec!["1", "4", "client"]);
+        let mut output = Vec::new();
+        engine.write_accounts_json(&mut output).unwrap();
+        let accounts: Vec<serde_json::Value> = serde_json::from_slice(&output).unwrap();
+        assert_eq!(accounts.len(), 2);
+        let mut output = Vec::new();
+        engine.write_accounts_jsonl(&mut output).unwrap();
+        assert_eq!(std::str::from_utf8(&output).unwrap().lines().count(), 2);
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        engine
+            .write_accounts_with(&mut writer, |acct| (acct.id,))
+            .unwrap();
+        let output = writer.into_inner().unwrap();
+        let mut clients: Vec<&str> = std::str::from_utf8(&output).unwrap().lines().collect();
+        clients.sort_unstable();
+        assert_eq!(clients, vec!["1", "4"]);
+
+        let mut engine = TransactionEngine::new().with_no_activity_output(NoActivityOutput::Mark);
+        engine.process_lines(&lines).unwrap();
+        let mut output = Vec::new();
+        engine
+            .write_accounts_columns(&mut output, &[AccountColumn::Client])
+            .unwrap();
+        let mut rows: Vec<&str> = std::str::from_utf8(&output).unwrap().lines().collect();
+        rows.sort_unstable();
+        assert_eq!(
+            rows,
+            vec![
+                "1,false",
+                "2,true",
+                "3,true",
+                "4,false",
+                "client,no_activity"
+            ]
+        );
+        #[derive(Serialize)]
+        struct Client {
+            client: u16,
+        }
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        engine
+            .write_accounts_with(&mut writer, |acct| Client { client: acct.id })
+            .unwrap();
+        let output = writer.into_inner().unwrap();
+        let mut marked_rows: Vec<&str> = std::str::from_utf8(&output).unwrap().lines().collect();
+        marked_rows.sort_unstable();
+        assert_eq!(marked_rows, rows);
+        let mut output = Vec::new();
+        engine.write_accounts_jsonl(&mut output).unwrap();
+        let marked = std::str::from_utf8(&output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|acct| acct["no_activity"] == serde_json::Value::Bool(true))
+            .count();
+        assert_eq!(marked, 2);
+    }
+
+    #[test]
+    fn withdrawal_success_rates() {
+        let mut engine = TransactionEngine::new();
+        engine
+            .process_lines(&[
+                "deposit,1,1,5.0",
+                "withdrawal,1,2,2.0",
+                "withdrawal,1,3,4.0",
+                "withdrawal,1,4,3.0",
+                "withdrawal,1,5,1.0",
+                "withdrawal,2,6,1.0",
+                "deposit,3,7,1.0",
+            ])
+            .unwrap();
+        assert_eq!(engine.withdrawal_success_rate(1), Some(0.5));
+        assert_eq!(engine.withdrawal_success_rate(2), Some(0.0));
+        assert_eq!(engine.withdrawal_success_rate(3), None);
+        assert_eq!(engine.withdrawal_success_rate(4), None);
     }
 
     #[test]
@@ -0,0 +1,195 @@
+use crate::engine::TxId;
+use rust_decimal::Decimal;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+/// Errors raised by the engine that callers may want to tell apart. These are surfaced through
+/// `anyhow::Error` like every other failure and can be recovered with `downcast_ref`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineError {
+    /// The input did not begin with a header row naming the expected columns
+    MissingHeader,
+    /// A deposit was for an amount of zero, which moves no funds
+    ZeroDeposit,
+    /// A deposit was smaller than the configured minimum deposit
+    BelowMinimumDeposit { amount: Decimal, minimum: Decimal },
+    /// A deposit was smaller than the configured dust threshold
+    DustDeposit { amount: Decimal, threshold: Decimal },
+    /// A deposit would have taken an account's total above the configured maximum balance
+    AboveMaximumBalance { amount: Decimal, maximum: Decimal },
+    /// A transaction was for a client not on the configured allowlist, for the reserved client Id
+    /// zero when that is rejected, or for the control account
+    ClientNotAllowed(u16),
+    /// A transaction was given while the engine is globally frozen and set to reject them
+    GloballyFrozen,
+    /// A resolve or chargeback referred to a transaction that isn't disputed under strict
+    /// settlement
+    ResolveWithoutDispute(TxId),
+    /// A deposit was disputed for more than the client's available balance when such disputes
+    /// are rejected
+    DisputeExceedsAvailable(TxId),
+    /// A resolve would have released more than the account holds under
+    /// `ResolveShortfallPolicy::Reject`
+    ResolveExceedsHeld(TxId),
+    /// A chargeback would have taken the account's total below zero under
+    /// `NegativeTotalPolicy::Reject`
+    ChargebackExceedsTotal(TxId),
+    /// A dispute, resolve or chargeback referred to a transaction other than a deposit or
+    /// withdrawal
+    NonDisputableType(TxId),
+    /// A dispute, resolve or chargeback was out of order for the transaction it referred to, such
+    /// as a resolve before any dispute, under lifecycle enforcement
+    LifecycleViolation { tx_id: TxId, action: &'static str },
+    /// A dispute, resolve or chargeback went over the configured cap on actions for a single
+    /// transaction
+    DisputeActionLimit(TxId),
+    /// A dispute would have gone over the configured cap on disputes open at once across the
+    /// engine
+    OpenDisputeLimit(TxId),
+    /// An amount started out as a number but carried on with other characters, given as the full
+    /// text of the amount
+    MalformedAmount(String),
+    /// A deposit or withdrawal reused the Id of an earlier one
+    DuplicateTransaction(TxId),
+    /// A deposit or withdrawal couldn't be reversed by `undo_file`, with the reason why
+    UndoRefused { tx_id: TxId, reason: &'static str },
+    /// A record of the input had no transaction Id
+    MissingTxId,
+    /// A record of the input could not be deserialized into a transaction
+    Deserialize(String),
+    /// Any other failure while processing a transaction
+    Processing(String),
+}
+
+impl EngineError {
+    /// Recovers the `EngineError` behind an error returned from the engine, or wraps the full
+    /// message of any other failure as a `Processing` error.
+    pub fn from_anyhow(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<EngineError>() {
+            Some(engine_err) => engine_err.clone(),
+            None => EngineError::Processing(format!("{:#}", err)),
+        }
+    }
+
+    /// A short stable name for the kind of error, for machine consumption.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EngineError::MissingHeader => "MissingHeader",
+            EngineError::ZeroDeposit => "ZeroDeposit",
+            EngineError::BelowMinimumDeposit { .. } => "BelowMinimumDeposit",
+            EngineError::DustDeposit { .. } => "DustDeposit",
+            EngineError::AboveMaximumBalance { .. } => "AboveMaximumBalance",
+            EngineError::ClientNotAllowed(_) => "ClientNotAllowed",
+            EngineError::GloballyFrozen => "GloballyFrozen",
+            EngineError::ResolveWithoutDispute(_) => "ResolveWithoutDispute",
+            EngineError::DisputeExceedsAvailable(_) => "DisputeExceedsAvailable",
+            EngineError::ResolveExceedsHeld(_) => "ResolveExceedsHeld",
+            EngineError::ChargebackExceedsTotal(_) => "ChargebackExceedsTotal",
+            EngineError::NonDisputableType(_) => "NonDisputableType",
+            EngineError::LifecycleViolation { .. } => "LifecycleViolation",
+            EngineError::DisputeActionLimit(_) => "DisputeActionLimit",
+            EngineError::OpenDisputeLimit(_) => "OpenDisputeLimit",
+            EngineError::MalformedAmount(_) => "MalformedAmount",
+            EngineError::DuplicateTransaction(_) => "DuplicateTransaction",
+            EngineError::UndoRefused { .. } => "UndoRefused",
+            EngineError::MissingTxId => "MissingTxId",
+            EngineError::Deserialize(_) => "Deserialize",
+            EngineError::Processing(_) => "Processing",
+        }
+    }
+}
+
+/// How the engine handles a record that fails to deserialize or process while reading input.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop processing and return the error
+    #[default]
+    Abort,
+    /// Drop the failed record and carry on with the next one
+    Skip,
+    /// Drop the failed record but keep the error so it can be reported, then carry on
+    Collect,
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::MissingHeader => {
+                write!(f, "Input is missing the `type,client,tx,amount` header")
+            }
+            EngineError::ZeroDeposit => write!(f, "Deposit is for an amount of zero"),
+            EngineError::BelowMinimumDeposit { amount, minimum } => write!(
+                f,
+                "Deposit of {} is below the minimum deposit of {}",
+                amount, minimum
+            ),
+            EngineError::DustDeposit { amount, threshold } => write!(
+                f,
+                "Deposit of {} is below the dust threshold of {}",
+                amount, threshold
+            ),
+            EngineError::AboveMaximumBalance { amount, maximum } => write!(
+                f,
+                "Deposit of {} would take the account above the maximum balance of {}",
+                amount, maximum
+            ),
+            EngineError::ClientNotAllowed(client_id) => {
+                write!(f, "Client {} is not allowed", client_id)
+            }
+            EngineError::GloballyFrozen => write!(f, "All accounts are frozen"),
+            EngineError::ResolveWithoutDispute(tx_id) => {
+                write!(f, "Transaction {} is not disputed", tx_id)
+            }
+            EngineError::DisputeExceedsAvailable(tx_id) => write!(
+                f,
+                "Disputing transaction {} would take available below zero",
+                tx_id
+            ),
+            EngineError::ResolveExceedsHeld(tx_id) => write!(
+                f,
+                "Resolving transaction {} would release more than is held",
+                tx_id
+            ),
+            EngineError::ChargebackExceedsTotal(tx_id) => write!(
+                f,
+                "Charging back transaction {} would take the total below zero",
+                tx_id
+            ),
+            EngineError::NonDisputableType(tx_id) => write!(
+                f,
+                "Transaction {} is not a deposit or withdrawal so can't be disputed",
+                tx_id
+            ),
+            EngineError::LifecycleViolation { tx_id, action } => write!(
+                f,
+                "Transaction {} is not in a state that allows a {}",
+                tx_id, action
+            ),
+            EngineError::DisputeActionLimit(tx_id) => write!(
+                f,
+                "Transaction {} has reached the limit on dispute actions",
+                tx_id
+            ),
+            EngineError::OpenDisputeLimit(tx_id) => write!(
+                f,
+                "Disputing transaction {} would exceed the limit on open disputes",
+                tx_id
+            ),
+            EngineError::MalformedAmount(amount) => {
+                write!(f, "Amount `{}` has trailing characters", amount)
+            }
+            EngineError::DuplicateTransaction(tx_id) => {
+                write!(f, "Transaction {} has already been seen", tx_id)
+            }
+            EngineError::UndoRefused { tx_id, reason } => {
+                write!(f, "Can't undo transaction {} as {}", tx_id, reason)
+            }
+            EngineError::MissingTxId => write!(f, "Record is missing a transaction Id"),
+            EngineError::Deserialize(msg) => write!(f, "Failed to deserialize record: {}", msg),
+            EngineError::Processing(msg) => write!(f, "Failed to process transaction: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
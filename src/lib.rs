@@ -0,0 +1,4 @@
+mod bloom;
+pub mod engine;
+pub mod error;
+mod rng;
@@ -1,29 +1,389 @@
-use crate::engine::Transaction;
-use crate::engine::TransactionEngine;
+use anyhow::Context;
+use serde::Serialize;
+use std::collections::HashSet;
 use std::env;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::process;
+use transactions::engine::TransactionEngine;
 
-mod engine;
+const USAGE: &str = "Expected arguments `[--summary] [--bom] [--grouped | --baseline <accounts \
+                     path>] [--seen-files <path> [--force]] <input path>`";
+
+/// The options given on the command line.
+#[derive(Debug, Default)]
+struct Args<'a> {
+    input_path: &'a str,
+    baseline_path: Option<&'a str>,
+    // Print a one line summary of the run to stderr
+    summary: bool,
+    // Begin the output with a UTF-8 byte order mark for Excel
+    bom: bool,
+    // Print each client's account as soon as their group of transactions ends
+    grouped: bool,
+    // A sidecar recording the content hash of every input processed, used to refuse inputs that
+    // have already been processed
+    seen_files_path: Option<&'a str>,
+    // Process the input even if the sidecar shows it has already been processed
+    force: bool,
+}
+
+impl<'a> Args<'a> {
+    fn parse(args: &'a [String]) -> anyhow::Result<Self> {
+        let mut parsed = Args::default();
+        let mut input_path = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--baseline" => {
+                    parsed.baseline_path = Some(args.next().context(USAGE)?.as_str());
+                }
+                "--summary" => parsed.summary = true,
+                "--bom" => parsed.bom = true,
+                "--grouped" => parsed.grouped = true,
+                "--seen-files" => {
+                    parsed.seen_files_path = Some(args.next().context(USAGE)?.as_str());
+                }
+                "--force" => parsed.force = true,
+                path if input_path.is_none() => input_path = Some(path),
+                _ => return Err(anyhow::Error::msg(USAGE)),
+            }
+        }
+        let force_without_sidecar = parsed.force && parsed.seen_files_path.is_none();
+        if (parsed.grouped && parsed.baseline_path.is_some()) || force_without_sidecar {
+            return Err(anyhow::Error::msg(USAGE));
+        }
+        parsed.input_path = input_path.context(USAGE)?;
+        Ok(parsed)
+    }
+}
+
+/// A file named on the command line that couldn't be opened.
+#[derive(Debug, Clone, PartialEq)]
+enum InputError {
+    NotFound(String),
+    PermissionDenied(String),
+    IsDirectory(String),
+    Unreadable(String, String),
+    // The input's content hash is recorded in the seen files sidecar
+    AlreadyProcessed(String),
+}
+
+impl InputError {
+    /// A short stable name for the kind of error, for machine consumption.
+    fn kind(&self) -> &'static str {
+        match self {
+            InputError::NotFound(_) => "NotFound",
+            InputError::PermissionDenied(_) => "PermissionDenied",
+            InputError::IsDirectory(_) => "IsDirectory",
+            InputError::Unreadable(..) => "Unreadable",
+            InputError::AlreadyProcessed(_) => "AlreadyProcessed",
+        }
+    }
+
+    fn path(&self) -> &str {
+        match self {
+            InputError::NotFound(path)
+            | InputError::PermissionDenied(path)
+            | InputError::IsDirectory(path)
+            | InputError::Unreadable(path, _)
+            | InputError::AlreadyProcessed(path) => path,
+        }
+    }
+}
+
+impl Display for InputError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::NotFound(path) => write!(f, "`{}` does not exist", path),
+            InputError::PermissionDenied(path) => {
+                write!(f, "Permission denied reading `{}`", path)
+            }
+            InputError::IsDirectory(path) => write!(f, "`{}` is a directory", path),
+            InputError::Unreadable(path, msg) => write!(f, "Could not read `{}`: {}", path, msg),
+            InputError::AlreadyProcessed(path) => write!(
+                f,
+                "`{}` has already been processed, use `--force` to process it again",
+                path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+/// A failure of the program in the shape it is reported as JSON on stderr.
+#[derive(Debug, Serialize)]
+struct ErrorReport<'a> {
+    kind: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'a str>,
+    message: String,
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Expected only 1 argument representing the input path")
-    }
-    let file_path_arg = &args[1];
-    let mut rdr = csv::Reader::from_path(file_path_arg).expect("Could not read from path");
-    let deserialized_records = rdr.deserialize::<Transaction>();
-    let mut engine = TransactionEngine::new();
-    for tx_res in deserialized_records {
-        let tx = tx_res.expect("Failed to deserialize record");
-        engine
-            .process_transaction(tx)
-            .expect("Failed to process transaction");
-    }
-    // Print the CSV header
-    println!("client,available,held,total,locked");
-    let accounts = engine.retrieve_accounts();
-    // Print all the account records in CSV format via their `Display` impl
-    for account in accounts {
-        println!("{}", account);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args, io::stdout().lock(), io::stderr().lock()) {
+        let report = match err.downcast_ref::<InputError>() {
+            Some(input_err) => ErrorReport {
+                kind: input_err.kind(),
+                path: Some(input_err.path()),
+                message: input_err.to_string(),
+            },
+            None => ErrorReport {
+                kind: "Failed",
+                path: None,
+                message: format!("{:#}", err),
+            },
+        };
+        eprintln!(
+            "{}",
+            serde_json::to_string(&report).expect("Error report is always serializable")
+        );
+        process::exit(1);
+    }
+}
+
+/// Opens a file named on the command line, telling apart the common reasons it can't be read.
+fn open_input(path: &str) -> Result<File, InputError> {
+    let to_input_error = |err: io::Error| match err.kind() {
+        ErrorKind::NotFound => InputError::NotFound(path.to_string()),
+        ErrorKind::PermissionDenied => InputError::PermissionDenied(path.to_string()),
+        _ => InputError::Unreadable(path.to_string(), err.to_string()),
+    };
+    // Opening a directory succeeds on some platforms so check for one up front
+    if fs::metadata(path).map_err(to_input_error)?.is_dir() {
+        return Err(InputError::IsDirectory(path.to_string()));
+    }
+    File::open(path).map_err(to_input_error)
+}
+
+/// Processes the input named by the arguments, writing the accounts to `stdout` and anything
+/// meant for the person running the program to `stderr`.
+fn run<O: Write, E: Write>(args: &[String], stdout: O, mut stderr: E) -> anyhow::Result<()> {
+    let args = Args::parse(args)?;
+    let mut file = open_input(args.input_path)?;
+    let unreadable =
+        |err: io::Error| InputError::Unreadable(args.input_path.to_string(), err.to_string());
+    // The input is hashed up front so it can be refused before anything is processed, then
+    // recorded once processed unless it already was
+    let unseen_hash = match args.seen_files_path {
+        Some(seen_files_path) => {
+            let hash = content_hash(&mut file).map_err(unreadable)?;
+            file.seek(SeekFrom::Start(0)).map_err(unreadable)?;
+            match read_seen_files(seen_files_path)?.contains(&hash) {
+                true if !args.force => {
+                    return Err(InputError::AlreadyProcessed(args.input_path.to_string()).into());
+                }
+                true => None,
+                false => Some((seen_files_path, hash)),
+            }
+        }
+        None => None,
+    };
+    let engine = match args.baseline_path {
+        Some(baseline_path) => {
+            // Only the accounts which changed relative to the baseline are printed
+            let baseline = open_input(baseline_path)?;
+            let mut engine = TransactionEngine::from_accounts_csv(baseline)
+                .context("Failed to load baseline")?
+                .with_output_bom(args.bom);
+            let changed = engine
+                .process_reader_changed(file)
+                .context("Failed to process transactions")?;
+            engine.write_selected_accounts_with_warnings(stdout, changed, &mut stderr)?;
+            engine
+        }
+        None if args.grouped => {
+            let mut engine = TransactionEngine::new().with_output_bom(args.bom);
+            engine
+                .process_reader_grouped(file, stdout, &mut stderr)
+                .context("Failed to process transactions")?;
+            engine
+        }
+        None => {
+            let mut engine = TransactionEngine::new().with_output_bom(args.bom);
+            engine
+                .process_reader(file)
+                .context("Failed to process transactions")?;
+            engine.write_accounts_with_warnings(stdout, &mut stderr)?;
+            engine
+        }
+    };
+    if let Some((seen_files_path, hash)) = unseen_hash {
+        record_seen_file(seen_files_path, hash, args.input_path)?;
+    }
+    if args.summary {
+        write_summary(&engine, &mut stderr).context("Failed to write summary")?;
+    }
+    Ok(())
+}
+
+/// A 64-bit FNV-1a hash of everything the reader produces. This is stable across builds and
+/// platforms so it can be persisted between runs.
+fn content_hash<R: Read>(mut reader: R) -> io::Result<u64> {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut buf = [0; 8192];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            return Ok(hash);
+        }
+        for byte in &buf[..read] {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Reads the content hashes recorded in the seen files sidecar, which holds a line for each
+/// input processed with its hash in hex followed by its path. A sidecar that doesn't exist yet
+/// has no hashes recorded.
+fn read_seen_files(path: &str) -> anyhow::Result<HashSet<u64>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(err) => return Err(InputError::Unreadable(path.to_string(), err.to_string()).into()),
+    };
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let hash = line.split_whitespace().next().unwrap_or_default();
+            u64::from_str_radix(hash, 16)
+                .with_context(|| format!("Invalid hash `{}` in `{}`", hash, path))
+        })
+        .collect()
+}
+
+/// Appends the input's content hash to the seen files sidecar, creating it if needed.
+fn record_seen_file(path: &str, hash: u64, input_path: &str) -> anyhow::Result<()> {
+    let mut sidecar = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open `{}`", path))?;
+    writeln!(sidecar, "{:016x} {}", hash, input_path)
+        .with_context(|| format!("Failed to write to `{}`", path))
+}
+
+/// Writes a one line summary of the health of the run.
+fn write_summary<E: Write>(engine: &TransactionEngine, mut stderr: E) -> io::Result<()> {
+    let (accounts, locked) = engine
+        .retrieve_accounts()
+        .fold((0, 0), |(accounts, locked), acct| {
+            (accounts + 1, locked + acct.locked() as usize)
+        });
+    writeln!(
+        stderr,
+        "accounts={} locked={} processed={} skipped={} errors={}",
+        accounts,
+        locked,
+        engine.transactions_seen(),
+        engine.skipped_rows(),
+        engine.errors().len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn summary_written_to_stderr() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/advanced.csv");
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        run(&args(&["--summary", path]), &mut stdout, &mut stderr).unwrap();
+        assert_eq!(
+            String::from_utf8(stderr).unwrap(),
+            "accounts=4 locked=1 processed=13 skipped=0 errors=0\n"
+        );
+        assert!(String::from_utf8(stdout)
+            .unwrap()
+            .starts_with("client,available,held,total,locked\n"));
+
+        // Nothing is written to stderr without the flag
+        let mut stderr = Vec::new();
+        run(&args(&[path]), io::sink(), &mut stderr).unwrap();
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    fn missing_input_file() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/missing.csv");
+        let err = run(&args(&[path]), io::sink(), io::sink()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InputError>(),
+            Some(&InputError::NotFound(path.to_string()))
+        );
+        let input_path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/basic.csv");
+        let err = run(
+            &args(&["--baseline", path, input_path]),
+            io::sink(),
+            io::sink(),
+        )
+        .unwrap_err();
+        assert_eq!(err.downcast_ref::<InputError>().unwrap().kind(), "NotFound");
+    }
+
+    #[test]
+    fn input_refused_once_processed() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/advanced.csv");
+        let seen_files = std::env::temp_dir().join(format!("seen-files-{}", process::id()));
+        let seen_files = seen_files.to_str().unwrap();
+        let mut stdout = Vec::new();
+        run(
+            &args(&["--seen-files", seen_files, path]),
+            &mut stdout,
+            io::sink(),
+        )
+        .unwrap();
+        assert!(!stdout.is_empty());
+
+        let mut stdout = Vec::new();
+        let err = run(
+            &args(&["--seen-files", seen_files, path]),
+            &mut stdout,
+            io::sink(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InputError>(),
+            Some(&InputError::AlreadyProcessed(path.to_string()))
+        );
+        assert!(stdout.is_empty());
+
+        // Forcing it processes the input again without recording it twice
+        let args = args(&["--seen-files", seen_files, "--force", path]);
+        run(&args, io::sink(), io::sink()).unwrap();
+        assert_eq!(fs::read_to_string(seen_files).unwrap().lines().count(), 1);
+        fs::remove_file(seen_files).unwrap();
+    }
+
+    #[test]
+    fn input_path_is_directory() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data");
+        let mut stdout = Vec::new();
+        let err = run(&args(&[path]), &mut stdout, io::sink()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InputError>(),
+            Some(&InputError::IsDirectory(path.to_string()))
+        );
+        assert!(stdout.is_empty());
     }
 }